@@ -11,6 +11,7 @@ import {
 } from "@/components/ui/card";
 import { Separator } from "@/components/ui/separator";
 import Database from "@tauri-apps/plugin-sql";
+import { getDatabaseUrl } from "@/components/utils/db-util";
 import { PlayerControls } from "@/components/player-control";
 import { usePlayerControls } from "@/components/hooks/usePlayerControls";
 import PageHeader from "@/components/page-header";
@@ -43,13 +44,11 @@ export default function Home() {
   const [isSeeking] = useState(false);
   const [TrackListRefreshTrigger, setTrackListRefreshTrigger] = useState(false);
   // Constants and functions
-  const dbUrl = "sqlite:rwave.db";
-
   const { handlePlay, handlePause, handleNext, handleLast } =
     usePlayerControls();
 
   const getTrackAlbum = async (track: Track) => {
-    const db = await Database.load(dbUrl);
+    const db = await Database.load(await getDatabaseUrl());
     const albumArr = (await db.select(
       "SELECT Name FROM Albums WHERE AlbumID = $1",
       [track.AlbumID]
@@ -63,7 +62,7 @@ export default function Home() {
   };
 
   const getTrackArtist = async (track: Track) => {
-    const db = await Database.load(dbUrl);
+    const db = await Database.load(await getDatabaseUrl());
     const artistArr = (await db.select(
       "SELECT Name FROM Artists WHERE ArtistID = $1",
       [track.ArtistID]
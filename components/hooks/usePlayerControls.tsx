@@ -96,7 +96,7 @@ export const usePlayerControls = () => {
       filters: [
         {
           name: "Track File",
-          extensions: ["mp3"],
+          extensions: ["mp3", "flac", "m4a", "ogg", "opus", "wav", "ape", "wv", "mid", "midi"],
         },
       ],
     });
@@ -1,10 +1,12 @@
 "use client";
 
+import { invoke } from "@tauri-apps/api/core";
 import Database from "@tauri-apps/plugin-sql";
 import { Playlist } from "../context/playlistcontext";
 import { Track } from "../context/trackcontext";
 
-const dbURL = "sqlite:rwave.db";
+// The database of the active library profile, under the app data directory
+export const getDatabaseUrl = () => invoke<string>("get_database_url");
 
 interface DBinfo {
   seq: number;
@@ -12,10 +14,13 @@ interface DBinfo {
   file: string;
 }
 export const fetchAllTracksFromPlaylist = async (pl: Playlist) => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   const tracks = (await db.select(
-    "SELECT * FROM Tracks JOIN TrackPlaylist \
-    ON Tracks.TrackID = TrackPlaylist.TrackID \
+    // `Tracks.Path` is relative to its library root, `TrackFiles.FullPath` is what plays
+    "SELECT Tracks.TrackID, Tracks.Name, TrackFiles.FullPath AS Path, \
+    Tracks.ArtistID, Tracks.AlbumID, Tracks.Duration \
+    FROM Tracks JOIN TrackFiles ON Tracks.TrackID = TrackFiles.TrackID \
+    JOIN TrackPlaylist ON Tracks.TrackID = TrackPlaylist.TrackID \
     WHERE TrackPlaylist.PlaylistID = $1",
     [pl.playlist_id]
   )) as Array<Track>;
@@ -23,13 +28,13 @@ export const fetchAllTracksFromPlaylist = async (pl: Playlist) => {
 };
 
 export const getDatabasePath = async () => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   const res = (await db.select("PRAGMA database_list", [])) as Array<DBinfo>;
   return res[0].file;
 };
 
 export const addTrackToPlaylist = async (track: Track, playlist: Playlist) => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   // "INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID) VALUES (?1, ?2)"
   await db.execute(
     "INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID) VALUES ($1, $2)",
@@ -41,12 +46,12 @@ export const addTrackToPlaylist = async (track: Track, playlist: Playlist) => {
 };
 
 export const createNewPlaylist = async (name: string) => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   await db.execute("INSERT INTO Playlists (Name) VALUES ($1)", [name]);
 };
 
 export const deletePlaylist = async (id: number) => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   await db.execute("DELETE FROM TrackPlaylist WHERE PlaylistID = $1", [id]);
   await db.execute("DELETE FROM Playlists WHERE PlaylistID = $1", [id]);
 };
@@ -55,7 +60,7 @@ export const deleteTrackFromPlaylist = async (
   trackID: number,
   playlistID: number
 ) => {
-  const db = await Database.load(dbURL);
+  const db = await Database.load(await getDatabaseUrl());
   await db.execute(
     "DELETE FROM TrackPlaylist WHERE TrackID = $1 AND PlaylistID = $2",
     [trackID, playlistID]
@@ -0,0 +1,195 @@
+use crate::db::pool::DbPool;
+use lofty::file::TaggedFileExt;
+use lofty::picture::PictureType;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tauri::http::{header, Request, Response, StatusCode};
+
+/// Name of the URI scheme serving the thumbnails, e.g. `rwave-art://album/42`
+pub const URI_SCHEME: &str = "rwave-art";
+/// Thumbnails fit in a square of this size, in pixels
+const THUMBNAIL_SIZE: u32 = 512;
+/// Sidecar image names, by priority, matched case-insensitively
+const SIDECAR_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const SIDECAR_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Directory of the thumbnail cache, set once the app data directory is known.
+static ARTWORK_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_artwork_dir(dir: PathBuf) {
+    let _ = ARTWORK_DIR.set(dir);
+}
+
+/// The front cover embedded in the tags of the file (`APIC`, `covr`,
+/// `METADATA_BLOCK_PICTURE`), or any other picture if there is no front cover.
+pub fn embedded_picture(path: &str) -> Option<Vec<u8>> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    let pictures: Vec<_> = tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .collect();
+
+    pictures
+        .iter()
+        .find(|picture| picture.pic_type() == PictureType::CoverFront)
+        .or(pictures.first())
+        .map(|picture| picture.data().to_vec())
+}
+
+/// A `cover.jpg`, `folder.jpg`, ... image next to the file.
+pub fn sidecar_picture(path: &str) -> Option<Vec<u8>> {
+    let dir = Path::new(path).parent()?;
+    let candidates: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| {
+            file.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| SIDECAR_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .collect();
+
+    SIDECAR_NAMES.iter().find_map(|name| {
+        candidates
+            .iter()
+            .find(|file| {
+                file.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(|stem| stem.eq_ignore_ascii_case(name))
+                    .unwrap_or(false)
+            })
+            .and_then(|file| std::fs::read(file).ok())
+    })
+}
+
+/// Resizes `image` into the cache, named after the SHA-256 of the original bytes,
+/// so the same cover shared by many files or albums is stored once.
+/// Returns the hash and the path of the thumbnail.
+pub fn cache_thumbnail(image: &[u8]) -> Result<(String, PathBuf), Box<dyn std::error::Error>> {
+    let dir = ARTWORK_DIR
+        .get()
+        .ok_or("The artwork directory is not set")?;
+    let hash: String = Sha256::digest(image)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let thumbnail_path = dir.join(format!("{}.jpg", hash));
+
+    if !thumbnail_path.exists() {
+        std::fs::create_dir_all(dir)?;
+        let thumbnail = image::load_from_memory(image)?
+            .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+            .to_rgb8();
+
+        // Written aside then renamed, so a crash never leaves a truncated thumbnail in the cache
+        let partial_path = dir.join(format!("{}.jpg.part", hash));
+        thumbnail.save_with_format(&partial_path, image::ImageFormat::Jpeg)?;
+        std::fs::rename(&partial_path, &thumbnail_path)?;
+    }
+
+    Ok((hash, thumbnail_path))
+}
+
+/// Gives the album the artwork of `track_path`, from its tags or a sidecar image,
+/// unless the album already has one. Returns the `ArtworkID` of the album.
+pub fn store_album_artwork(
+    conn: &Connection,
+    album_id: i64,
+    track_path: &str,
+) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let current: Option<i64> = conn.query_row(
+        "SELECT ArtworkID FROM Albums WHERE AlbumID = ?",
+        params![album_id],
+        |row| row.get(0),
+    )?;
+    if current.is_some() {
+        return Ok(current);
+    }
+
+    let image = match embedded_picture(track_path).or_else(|| sidecar_picture(track_path)) {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+    let (hash, thumbnail_path) = cache_thumbnail(&image)?;
+
+    conn.execute(
+        "INSERT OR IGNORE INTO Artworks (Hash, Path) VALUES (?1, ?2)",
+        params![hash, thumbnail_path.to_string_lossy()],
+    )?;
+    let artwork_id: i64 = conn.query_row(
+        "SELECT ArtworkID FROM Artworks WHERE Hash = ?",
+        params![hash],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "UPDATE Albums SET ArtworkID = ?1 WHERE AlbumID = ?2",
+        params![artwork_id, album_id],
+    )?;
+
+    Ok(Some(artwork_id))
+}
+
+/// Splits a request URI into the kind of resource and its id.
+/// The webview sends `rwave-art://album/42` on Linux and macOS,
+/// and `http://rwave-art.localhost/album/42` on Windows.
+fn parse_uri(request: &Request<Vec<u8>>) -> Option<(String, i64)> {
+    let uri = request.uri();
+    let host = uri.host().unwrap_or_default();
+
+    let mut segments: Vec<&str> = uri.path().split('/').filter(|s| !s.is_empty()).collect();
+    if !host.is_empty() && !host.ends_with(".localhost") {
+        segments.insert(0, host);
+    }
+
+    match segments.as_slice() {
+        [kind, id] => Some((kind.to_string(), id.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn thumbnail_path(pool: &DbPool, kind: &str, id: i64) -> Option<String> {
+    let conn = pool.get().ok()?;
+    let sql = match kind {
+        "album" => {
+            "SELECT Artworks.Path FROM Albums
+            JOIN Artworks ON Albums.ArtworkID = Artworks.ArtworkID
+            WHERE Albums.AlbumID = ?"
+        }
+        "track" => {
+            "SELECT Artworks.Path FROM Tracks
+            JOIN Albums ON Tracks.AlbumID = Albums.AlbumID
+            JOIN Artworks ON Albums.ArtworkID = Artworks.ArtworkID
+            WHERE Tracks.TrackID = ?"
+        }
+        _ => return None,
+    };
+
+    conn.query_row(sql, params![id], |row| row.get(0))
+        .optional()
+        .ok()
+        .flatten()
+}
+
+/// Handles the `rwave-art` URI scheme: `album/<AlbumID>` or `track/<TrackID>`
+/// answers with the JPEG thumbnail of the album, or 404 if it has none.
+pub fn handle_request(pool: &DbPool, request: Request<Vec<u8>>) -> Response<Cow<'static, [u8]>> {
+    let image = parse_uri(&request)
+        .and_then(|(kind, id)| thumbnail_path(pool, &kind, id))
+        .and_then(|path| std::fs::read(path).ok());
+
+    match image {
+        Some(image) => Response::builder()
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .body(Cow::Owned(image))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Cow::Borrowed(&[][..]))
+            .unwrap(),
+    }
+}
@@ -1,9 +1,7 @@
+use crate::jobs::JobRegistry;
+use crate::metadata::read_track_metadata;
 use crate::player::{Player, PlayerEvent};
-use id3::{Tag, TagLike};
-use rodio::{source::Source, Decoder};
 use serde_derive::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
 use std::sync::Mutex;
 use tauri::{ipc::Channel, State};
 
@@ -13,6 +11,11 @@ pub fn load_track(player: State<'_, Mutex<Player>>, file_path: &str) {
     player.load(file_path);
 }
 
+#[tauri::command]
+pub fn enqueue_track(player: State<'_, Mutex<Player>>, file_path: &str) {
+    player.lock().unwrap().enqueue(file_path);
+}
+
 #[tauri::command]
 pub fn play_track(player: State<'_, Mutex<Player>>) {
     // dbg!("command::play_track invoked.");
@@ -31,6 +34,16 @@ pub fn seek_track(player: State<Mutex<Player>>, position: u64) {
     // unimplemented!("command::seek_module not implemented.")
 }
 
+#[tauri::command]
+pub fn next_chapter(player: State<'_, Mutex<Player>>) {
+    player.lock().unwrap().next_chapter();
+}
+
+#[tauri::command]
+pub fn previous_chapter(player: State<'_, Mutex<Player>>) {
+    player.lock().unwrap().previous_chapter();
+}
+
 #[tauri::command]
 pub fn subscribe_player_event(
     player: State<'_, Mutex<Player>>,
@@ -46,32 +59,24 @@ pub fn unsubscribe_player_event(player: State<'_, Mutex<Player>>, id: String) ->
     dbg!(player.lock().unwrap().unsubscribe_event(id))
 }
 
+/// Reads the tags of a track file of any supported format.
 #[tauri::command(rename_all = "snake_case")]
-pub fn parse_mp3_tags_command(path: String) -> Tags {
-    let tag = Tag::read_from_path(path.clone()).unwrap();
-    let file = BufReader::new(File::open(path).unwrap());
-    let source = Decoder::new(file).unwrap();
-
-    let title = tag
-        .title()
-        .map(|s| s.to_string())
-        .unwrap_or("UnknownTrack".into());
-    let artist = tag
-        .artist()
-        .map(|s| s.to_string())
-        .unwrap_or("UnknownArtist".into());
-    let album = tag
-        .album()
-        .map(|s| s.to_string())
-        .unwrap_or("UnknownAlbum".into());
-    let duration = source.total_duration().map(|d| d.as_secs()).unwrap_or(0);
+pub async fn parse_mp3_tags_command(
+    jobs: State<'_, JobRegistry>,
+    path: String,
+) -> Result<Tags, String> {
+    jobs.run("read_tags", None, move |_| {
+        let metadata = read_track_metadata(&path).unwrap_or_default();
 
-    Tags {
-        title,
-        artist,
-        album,
-        duration,
-    }
+        Ok::<_, String>(Tags {
+            title: metadata.title.unwrap_or("UnknownTrack".into()),
+            artist: metadata.artist.unwrap_or("UnknownArtist".into()),
+            album: metadata.album.unwrap_or("UnknownAlbum".into()),
+            duration: metadata.duration.unwrap_or(0),
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
 
 #[derive(Serialize, Deserialize)]
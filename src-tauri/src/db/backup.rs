@@ -0,0 +1,286 @@
+use super::error::LibraryError;
+use super::library::Library;
+use super::migrations::{current_version, latest_version, migrate, table_exists};
+use super::pool::DbPool;
+use super::reload_library;
+use crate::jobs::JobRegistry;
+use crate::watcher::LibraryWatcher;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde_derive::Serialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::State;
+
+/// How many automatic backups of a database are kept
+const KEPT_BACKUPS: usize = 5;
+
+/// Copies the database to `dest` with the online backup API, so the library can be
+/// used meanwhile. `dest` is replaced if it exists.
+pub fn backup_to(conn: &Connection, dest: &Path) -> rusqlite::Result<()> {
+    conn.backup(DatabaseName::Main, dest, None)
+}
+
+/// Backs up the database at `path` to `backups/<name>-<time>-v<version>.db` next to it,
+/// and deletes its oldest automatic backups beyond `KEPT_BACKUPS`.
+pub fn rotate_backup(
+    conn: &Connection,
+    path: &Path,
+    version: u32,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let dir = path.parent().unwrap_or(Path::new(".")).join("backups");
+    std::fs::create_dir_all(&dir)?;
+    let name = path
+        .file_stem()
+        .map_or("rwave".into(), |stem| stem.to_string_lossy());
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let dest = dir.join(format!("{}-{}-v{}.db", name, time, version));
+    backup_to(conn, &dest)?;
+
+    let prefix = format!("{}-", name);
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|backup| {
+            let file_name = backup.file_name().unwrap_or_default().to_string_lossy();
+            file_name.starts_with(&prefix) && file_name.ends_with(".db")
+        })
+        .collect();
+    backups.sort();
+    let stale = backups.len().saturating_sub(KEPT_BACKUPS);
+    for backup in &backups[..stale] {
+        std::fs::remove_file(backup)?;
+    }
+    Ok(dest)
+}
+
+/// Replaces the database with the rwave library at `src`, then migrates it.
+/// Libraries of a newer rwave are refused, and the current database is backed up first.
+pub fn restore_from(conn: &mut Connection, path: &Path, src: &Path) -> Result<(), Box<dyn Error>> {
+    let source = Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = current_version(&source)?;
+    if version == 0 && !table_exists(&source, "Tracks")? {
+        return Err(format!("{} is not an rwave library", src.display()).into());
+    }
+    if version > latest_version() {
+        return Err(format!(
+            "{} is from a newer version of rwave (schema {}, this one reads up to {})",
+            src.display(),
+            version,
+            latest_version()
+        )
+        .into());
+    }
+    let integrity = integrity_errors(&source)?;
+    if !integrity.is_empty() {
+        return Err(format!("{} is damaged: {}", src.display(), integrity.join(", ")).into());
+    }
+    drop(source);
+
+    rotate_backup(conn, path, current_version(conn)?)?;
+    conn.restore(
+        DatabaseName::Main,
+        src,
+        None::<fn(rusqlite::backup::Progress)>,
+    )?;
+    migrate(conn)?;
+    Ok(())
+}
+
+/// A row whose foreign key points nowhere, from `PRAGMA foreign_key_check`.
+#[derive(Serialize)]
+pub struct ForeignKeyViolation {
+    pub table: String,
+    pub rowid: Option<i64>,
+    /// The table the key refers to
+    pub parent: String,
+}
+
+#[derive(Serialize)]
+pub struct PlaylistEntry {
+    pub playlist_id: i64,
+    pub track_id: i64,
+}
+
+/// Result of `check`. The library is sound when every list is empty.
+#[derive(Serialize)]
+pub struct CheckReport {
+    pub ok: bool,
+    /// Problems found by `PRAGMA integrity_check`
+    pub integrity: Vec<String>,
+    pub foreign_keys: Vec<ForeignKeyViolation>,
+    /// IDs of the tracks whose artist doesn't exist
+    pub tracks_without_artist: Vec<i64>,
+    /// Playlist entries whose track doesn't exist
+    pub missing_playlist_tracks: Vec<PlaylistEntry>,
+}
+
+fn integrity_errors(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let messages = conn
+        .prepare("PRAGMA integrity_check")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(messages.into_iter().filter(|m| m != "ok").collect())
+}
+
+/// Checks the database file, its foreign keys, and the references rwave relies on.
+pub fn check(conn: &Connection) -> rusqlite::Result<CheckReport> {
+    let integrity = integrity_errors(conn)?;
+    let foreign_keys = conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map([], |row| {
+            Ok(ForeignKeyViolation {
+                table: row.get(0)?,
+                rowid: row.get(1)?,
+                parent: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let tracks_without_artist = conn
+        .prepare(
+            "SELECT TrackID FROM Tracks
+            WHERE ArtistID IS NULL OR ArtistID NOT IN (SELECT ArtistID FROM Artists)",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let missing_playlist_tracks = conn
+        .prepare(
+            "SELECT PlaylistID, TrackID FROM TrackPlaylist
+            WHERE TrackID NOT IN (SELECT TrackID FROM Tracks)",
+        )?
+        .query_map([], |row| {
+            Ok(PlaylistEntry {
+                playlist_id: row.get(0)?,
+                track_id: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(CheckReport {
+        ok: integrity.is_empty()
+            && foreign_keys.is_empty()
+            && tracks_without_artist.is_empty()
+            && missing_playlist_tracks.is_empty(),
+        integrity,
+        foreign_keys,
+        tracks_without_artist,
+        missing_playlist_tracks,
+    })
+}
+
+/// Copy the library to the file `dest`, while it stays usable.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn backup_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    dest: String,
+) -> Result<(), LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("backup_library", None, move |_| {
+        Library::from_pool(&pool)?.backup(Path::new(&dest))
+    })
+    .await?
+}
+
+/// Replace the library with the backup at `src`. The frontend must reload its data.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn restore_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    src: String,
+) -> Result<(), LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("restore_library", None, move |_| {
+        Library::from_pool(&pool)?.restore(Path::new(&src))
+    })
+    .await??;
+    reload_library(&db, &watcher);
+    Ok(())
+}
+
+/// Check the library for corruption and broken references.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn check_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<CheckReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("check_library", None, move |_| {
+        Library::from_pool(&pool)?.check()
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_up_restores_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("rwave-backup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rwave.db");
+        let mut conn = Connection::open(&path).unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute_batch("INSERT INTO Playlists (PlaylistID, Name) VALUES (2, 'Before');")
+            .unwrap();
+        assert!(check(&conn).unwrap().ok);
+
+        let saved = dir.join("saved.db");
+        backup_to(&conn, &saved).unwrap();
+        conn.execute(
+            "UPDATE Playlists SET Name = 'After' WHERE PlaylistID = 2",
+            [],
+        )
+        .unwrap();
+        restore_from(&mut conn, &path, &saved).unwrap();
+        let name: String = conn
+            .query_row(
+                "SELECT Name FROM Playlists WHERE PlaylistID = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(name, "Before");
+
+        let newer = Connection::open(dir.join("newer.db")).unwrap();
+        newer
+            .execute_batch(
+                "CREATE TABLE Tracks (TrackID INTEGER PRIMARY KEY); PRAGMA user_version = 1000;",
+            )
+            .unwrap();
+        assert!(restore_from(&mut conn, &path, &dir.join("newer.db")).is_err());
+
+        for version in 0..KEPT_BACKUPS + 2 {
+            std::fs::write(
+                dir.join("backups").join(format!("rwave-0-v{}.db", version)),
+                "",
+            )
+            .unwrap();
+        }
+        rotate_backup(&conn, &path, latest_version()).unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir.join("backups")).unwrap().count(),
+            KEPT_BACKUPS
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_broken_references() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+            INSERT INTO TrackPlaylist (TrackID, PlaylistID) VALUES (7, 1);",
+        )
+        .unwrap();
+        let report = check(&conn).unwrap();
+        assert!(!report.ok);
+        assert_eq!(report.missing_playlist_tracks.len(), 1);
+        assert_eq!(report.missing_playlist_tracks[0].track_id, 7);
+        assert_eq!(report.foreign_keys[0].parent, "Tracks");
+    }
+}
@@ -1,10 +1,14 @@
 //constants
 pub const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n";
 pub const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n";
+pub const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n";
 pub const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n";
 
-// pub const DB_URL: &str = "C:/Users/baiheyufei/AppData/rwave.db";
-pub const DB_URL: &str = "sqlite:rwave.db";
+/// File name of the database of the default profile, in the app data directory
+pub const DB_FILE_NAME: &str = "rwave.db";
+/// The database of earlier versions: `rusqlite` opened this literal file name in the
+/// working directory, while `tauri_plugin_sql` resolved it to `rwave.db` in the app config directory.
+pub const LEGACY_DB_URL: &str = "sqlite:rwave.db";
 
 pub const ACCESS_CONTROL_ALLOW_ORIGIN: &str =
     "Access-Control-Allow-Origin: http://localhost:3000\r\n";
@@ -0,0 +1,448 @@
+use super::entities::{Track, TrackColumns};
+use super::error::LibraryError;
+use super::import::{ImportFailure, BATCH_SIZE};
+use super::library::Library;
+use super::pool::DbPool;
+use super::roots::native_path;
+use crate::fingerprint::{self, fingerprint, similarity};
+use crate::hashing::audio_hash;
+use crate::jobs::JobRegistry;
+use rusqlite::{params, Connection, Result};
+use serde_derive::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::{ipc::Channel, State};
+
+/// Similarity above which two tracks are taken for the same recording, by default
+const DEFAULT_THRESHOLD: f32 = 0.8;
+/// Only tracks whose durations are this close, in seconds, are compared
+const DURATION_TOLERANCE: i64 = 3;
+/// Extensions of the lossless formats, preferred over any lossy encode
+const LOSSLESS_EXTENSIONS: [&str; 4] = ["flac", "wav", "aif", "aiff"];
+
+/// Set while `fingerprint_tracks` runs, so the startup job and the command don't overlap.
+static FINGERPRINTING: AtomicBool = AtomicBool::new(false);
+
+/// Tracks with the same audio, e.g. a file copied to two folders or re-tagged copies.
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    pub audio_hash: String,
+    pub tracks: Vec<Track>,
+}
+
+/// Hashes the audio of the tracks that have no `AudioHash` yet, or whose file changed.
+/// Files that can't be demuxed get an empty hash, so they aren't tried at every search.
+/// The hashes are committed `BATCH_SIZE` tracks at a time.
+/// `cancel` is checked between files, `on_progress` gets `(done, total)`.
+pub fn hash_missing_audio<F>(
+    conn: &mut Connection,
+    cancel: &AtomicBool,
+    mut on_progress: F,
+) -> Result<usize>
+where
+    F: FnMut(usize, usize),
+{
+    let tracks: Vec<(i64, String)> = conn
+        .prepare(
+            "SELECT TrackID, FullPath FROM Tracks JOIN TrackFiles USING (TrackID)
+            WHERE AudioHash IS NULL AND Missing = 0",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, native_path(row.get(1)?))))?
+        .collect::<Result<_>>()?;
+
+    let mut tx = conn.transaction()?;
+    let mut hashed = 0;
+    let total = tracks.len();
+    for (done, (track_id, path)) in tracks.into_iter().enumerate() {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        tx.execute(
+            "UPDATE Tracks SET AudioHash = ?1 WHERE TrackID = ?2",
+            params![audio_hash(&path).unwrap_or_default(), track_id],
+        )?;
+        hashed += 1;
+        on_progress(done + 1, total);
+        if (done + 1) % BATCH_SIZE == 0 {
+            tx.commit()?;
+            tx = conn.transaction()?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(hashed)
+}
+
+/// Groups the tracks sharing their audio hash, groups and tracks ordered by `TrackID`.
+/// The tracks without a hash are hashed first, see `hash_missing_audio`. When cancelled,
+/// the tracks not hashed yet are left out.
+pub fn duplicate_groups<F>(
+    conn: &mut Connection,
+    cancel: &AtomicBool,
+    on_progress: F,
+) -> Result<Vec<DuplicateGroup>>
+where
+    F: FnMut(usize, usize),
+{
+    hash_missing_audio(conn, cancel, on_progress)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) WHERE AudioHash IN (
+            SELECT AudioHash FROM Tracks WHERE AudioHash <> ''
+            GROUP BY AudioHash HAVING COUNT(*) > 1
+        )
+        ORDER BY TrackID",
+    )?;
+    let columns = TrackColumns::new(&stmt)?;
+    let tracks = stmt
+        .query_map([], |row| columns.read(row))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for track in tracks {
+        let hash = track.audio_hash.clone().unwrap_or_default();
+        match groups.iter_mut().find(|group| group.audio_hash == hash) {
+            Some(group) => group.tracks.push(track),
+            None => groups.push(DuplicateGroup {
+                audio_hash: hash,
+                tracks: vec![track],
+            }),
+        }
+    }
+
+    Ok(groups)
+}
+
+/// Result of `merge_tracks`.
+#[derive(Debug, Default, Serialize)]
+pub struct MergeReport {
+    /// Tracks removed from the library
+    pub merged: usize,
+    pub deleted_files: Vec<String>,
+    /// Files that could not be deleted, their tracks are merged anyway
+    pub failures: Vec<ImportFailure>,
+}
+
+/// The audio hash of `track_id`, hashing its file if it has none yet.
+fn track_audio_hash(conn: &Connection, track_id: i64) -> Result<String> {
+    let (hash, path): (Option<String>, String) = conn.query_row(
+        "SELECT AudioHash, FullPath FROM Tracks JOIN TrackFiles USING (TrackID)
+        WHERE TrackID = ?",
+        params![track_id],
+        |row| Ok((row.get(0)?, native_path(row.get(1)?))),
+    )?;
+    if let Some(hash) = hash {
+        return Ok(hash);
+    }
+
+    let hash = audio_hash(&path).unwrap_or_default();
+    conn.execute(
+        "UPDATE Tracks SET AudioHash = ?1 WHERE TrackID = ?2",
+        params![hash, track_id],
+    )?;
+    Ok(hash)
+}
+
+/// Folds the tracks `remove_ids` into `keep_id`: their playlists and play counts move
+/// to the kept track, which also takes their rating if it has none. The removed tracks
+/// are deleted from the library, and their files from the disk if `delete_files` is set.
+/// Nothing is merged unless every removed track has the same audio as the kept one.
+pub fn merge_tracks(
+    conn: &mut Connection,
+    keep_id: i64,
+    remove_ids: &[i64],
+    delete_files: bool,
+) -> std::result::Result<MergeReport, LibraryError> {
+    let tx = conn.transaction()?;
+    // Fails with `QueryReturnedNoRows` if the kept track doesn't exist
+    let keep_hash = track_audio_hash(&tx, keep_id)?;
+    for &remove_id in remove_ids.iter().filter(|&&id| id != keep_id) {
+        let hash = track_audio_hash(&tx, remove_id)?;
+        // An empty hash is a file that can't be demuxed, it matches nothing
+        if hash.is_empty() || hash != keep_hash {
+            return Err(LibraryError::Invalid(format!(
+                "Track {} is not a duplicate of track {}",
+                remove_id, keep_id
+            )));
+        }
+    }
+
+    let mut report = MergeReport::default();
+    let mut paths = Vec::new();
+    for &remove_id in remove_ids.iter().filter(|&&id| id != keep_id) {
+        let path: String = tx.query_row(
+            "SELECT FullPath FROM TrackFiles WHERE TrackID = ?",
+            params![remove_id],
+            |row| row.get(0).map(native_path),
+        )?;
+
+        tx.execute(
+            "INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID)
+            SELECT ?1, PlaylistID FROM TrackPlaylist WHERE TrackID = ?2",
+            params![keep_id, remove_id],
+        )?;
+        tx.execute(
+            "UPDATE Tracks SET
+                PlayCount = PlayCount + (SELECT PlayCount FROM Tracks WHERE TrackID = ?2),
+                Rating = COALESCE(Rating, (SELECT Rating FROM Tracks WHERE TrackID = ?2))
+            WHERE TrackID = ?1",
+            params![keep_id, remove_id],
+        )?;
+        tx.execute("DELETE FROM Tracks WHERE TrackID = ?", params![remove_id])?;
+
+        report.merged += 1;
+        paths.push(path);
+    }
+    tx.commit()?;
+
+    // Only once the library no longer references them
+    if delete_files {
+        for path in paths {
+            match std::fs::remove_file(&path) {
+                Ok(()) => report.deleted_files.push(path),
+                Err(e) => report.failures.push(ImportFailure {
+                    path,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Progress of `fingerprint_tracks`.
+#[derive(Clone, Serialize)]
+pub struct FingerprintProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Computes the acoustic fingerprint of the tracks that have none yet, or whose file
+/// changed. Files that can't be decoded get an empty one, so they aren't decoded again.
+/// Returns the number of tracks processed, 0 if fingerprinting is already running.
+/// `cancel` is checked between files, the fingerprints stored so far are kept.
+pub fn fingerprint_tracks<F>(
+    conn: &Connection,
+    cancel: &AtomicBool,
+    mut on_progress: F,
+) -> Result<usize>
+where
+    F: FnMut(&FingerprintProgress),
+{
+    if FINGERPRINTING.swap(true, Ordering::SeqCst) {
+        return Ok(0);
+    }
+
+    let result = (|| {
+        let tracks: Vec<(i64, String)> = conn
+            .prepare(
+                "SELECT TrackID, FullPath FROM Tracks JOIN TrackFiles USING (TrackID)
+                WHERE Fingerprint IS NULL AND Missing = 0",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, native_path(row.get(1)?))))?
+            .collect::<Result<_>>()?;
+
+        let mut progress = FingerprintProgress {
+            done: 0,
+            total: tracks.len(),
+        };
+        for (track_id, path) in tracks {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            // Decoding takes a while, each fingerprint is stored on its own
+            let bytes = fingerprint(&path)
+                .map(|words| fingerprint::to_bytes(&words))
+                .unwrap_or_default();
+            conn.execute(
+                "UPDATE Tracks SET Fingerprint = ?1 WHERE TrackID = ?2",
+                params![bytes, track_id],
+            )?;
+            progress.done += 1;
+            on_progress(&progress);
+        }
+        Ok(progress.done)
+    })();
+
+    FINGERPRINTING.store(false, Ordering::SeqCst);
+    result
+}
+
+/// A track of a `SimilarGroup`, with its similarity to the best one.
+#[derive(Serialize)]
+pub struct SimilarTrack {
+    pub track: Track,
+    pub similarity: f32,
+}
+
+/// Tracks that sound like the same recording, best quality first.
+#[derive(Serialize)]
+pub struct SimilarGroup {
+    pub best_track_id: i64,
+    pub tracks: Vec<SimilarTrack>,
+}
+
+/// Sort key of a track, lower is better: lossless first, then by bitrate and sample rate.
+fn quality_rank(track: &Track) -> (bool, i64, i64) {
+    let lossless = Path::new(&track.path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| LOSSLESS_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false);
+    (
+        !lossless,
+        -track.bitrate.unwrap_or(0),
+        -track.sample_rate.unwrap_or(0),
+    )
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+/// Groups the fingerprinted tracks whose fingerprints are at least `threshold` similar.
+/// Tracks not fingerprinted yet are left out, see `fingerprint_tracks`.
+pub fn similar_recordings(conn: &Connection, threshold: f32) -> Result<Vec<SimilarGroup>> {
+    let mut fingerprints: Vec<(i64, i64, Vec<u32>)> = conn
+        .prepare(
+            "SELECT TrackID, Duration, Fingerprint FROM Tracks
+            WHERE length(Fingerprint) > 0 AND Missing = 0",
+        )?
+        .query_map([], |row| {
+            let bytes: Vec<u8> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                row.get::<_, Option<i64>>(1)?.unwrap_or(0),
+                fingerprint::from_bytes(&bytes),
+            ))
+        })?
+        .collect::<Result<_>>()?;
+    fingerprints.sort_by_key(|(_, duration, _)| *duration);
+
+    // Different encodes of a recording have about the same duration,
+    // which spares comparing every pair of tracks
+    let mut parents: Vec<usize> = (0..fingerprints.len()).collect();
+    for i in 0..fingerprints.len() {
+        for j in i + 1..fingerprints.len() {
+            if fingerprints[j].1 - fingerprints[i].1 > DURATION_TOLERANCE {
+                break;
+            }
+            if similarity(&fingerprints[i].2, &fingerprints[j].2) >= threshold {
+                let (a, b) = (find_root(&mut parents, i), find_root(&mut parents, j));
+                parents[a] = b;
+            }
+        }
+    }
+
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); fingerprints.len()];
+    for i in 0..fingerprints.len() {
+        let root = find_root(&mut parents, i);
+        members[root].push(i);
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) WHERE TrackID = ?")?;
+    let mut groups = Vec::new();
+    for indices in members.into_iter().filter(|indices| indices.len() > 1) {
+        let mut tracks = indices
+            .into_iter()
+            .map(|i| {
+                let track = stmt.query_row(params![fingerprints[i].0], Track::from_row)?;
+                Ok((i, track))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        tracks.sort_by_key(|(_, track)| quality_rank(track));
+
+        let best = tracks[0].0;
+        groups.push(SimilarGroup {
+            best_track_id: fingerprints[best].0,
+            tracks: tracks
+                .into_iter()
+                .map(|(i, track)| SimilarTrack {
+                    track,
+                    similarity: similarity(&fingerprints[best].2, &fingerprints[i].2),
+                })
+                .collect(),
+        });
+    }
+    groups.sort_by_key(|group| group.best_track_id);
+
+    Ok(groups)
+}
+
+/// Fingerprint the tracks that have no acoustic fingerprint yet, streaming the progress
+/// on `on_progress`. Also runs in the background at startup.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn fingerprint_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    on_progress: Channel<FingerprintProgress>,
+) -> Result<usize, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("fingerprint_library", None, move |job| {
+        Library::from_pool(&pool)?.fingerprint(job.cancel_flag(), |progress| {
+            job.progress(progress.done, Some(progress.total));
+            let _ = on_progress.send(progress.clone());
+        })
+    })
+    .await?
+}
+
+/// Find the tracks that sound like the same recording, e.g. a FLAC and an MP3 of a song,
+/// with the best quality version first. `threshold` is the similarity from 0 to 1
+/// above which tracks are grouped, 0.8 by default.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn find_similar_recordings(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    threshold: Option<f32>,
+) -> Result<Vec<SimilarGroup>, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("find_similar_recordings", None, move |_| {
+        Library::from_pool(&pool)?.similar_recordings(threshold.unwrap_or(DEFAULT_THRESHOLD))
+    })
+    .await?
+}
+
+/// Find the groups of tracks with the same audio, whatever their tags.
+/// Hashes the audio of the tracks imported or changed since the last search first,
+/// reporting the progress of the job. When cancelled, the tracks not hashed yet are left out.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn find_duplicates(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<Vec<DuplicateGroup>, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("find_duplicates", None, move |job| {
+        Library::from_pool(&pool)?.duplicates(job.cancel_flag(), |done, total| {
+            job.progress(done, Some(total))
+        })
+    })
+    .await?
+}
+
+/// Merge duplicate tracks into `keep_id`, see `merge_tracks`.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn merge_duplicates(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    keep_id: i64,
+    remove_ids: Vec<i64>,
+    delete_files: Option<bool>,
+) -> Result<MergeReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("merge_duplicates", None, move |_| {
+        Library::from_pool(&pool)?.merge_duplicates(
+            keep_id,
+            &remove_ids,
+            delete_files.unwrap_or(false),
+        )
+    })
+    .await?
+}
@@ -1,13 +1,29 @@
+use super::roots::native_path;
+use rusqlite::{Result, Row, Statement};
 use serde_derive::{Deserialize, Serialize};
 
 /// Tracks
 /// - TrackID (Primary Key)
 /// - Name
-/// - Path
+/// - RootID (Foreign Key): Reference to the library root the path is relative to.
+/// - Path: Relative to the root with `/` separators, absolute without a root.
+///   `TrackFiles.FullPath` is the absolute path, and what `Track::path` holds.
 /// - ArtistID (Foreign Key): Reference to the artist.
 /// - AlbumID (Foreign Key): Reference to the album.
 /// - Duration
-#[derive(Serialize, Deserialize)]
+/// - LoopStart: Start of the loop region in sample frames, from `LOOPSTART` tags.
+/// - LoopLength: Length of the loop region in sample frames.
+/// - TrackNumber, DiscNumber, Year, Genre, AlbumArtist, Composer, Comment: From the tags.
+/// - Bitrate: Audio bitrate in kbit/s.
+/// - SampleRate
+/// - FileSize: In bytes.
+/// - MTime: Last modification time of the file, in seconds since the Unix epoch.
+/// - Missing: Set when the file is gone from its library folder.
+/// - ContentHash: Hash of the size and samples of the file, to recognize it once renamed.
+/// - AudioHash: Hash of the audio packets, without the tags, to find duplicates.
+/// - PlayCount
+/// - Rating: From 1 to 5, none if the track wasn't rated.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Track {
     pub track_id: Option<i32>,
     pub name: String,
@@ -15,12 +31,120 @@ pub struct Track {
     pub artist_id: Option<i32>,
     pub album_id: Option<i32>,
     pub duration: Option<i32>,
+    pub loop_start: Option<i64>,
+    pub loop_length: Option<i64>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub year: Option<i64>,
+    pub genre: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub bitrate: Option<i64>,
+    pub sample_rate: Option<i64>,
+    pub file_size: Option<i64>,
+    pub mtime: Option<i64>,
+    #[serde(default)]
+    pub missing: bool,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    #[serde(default)]
+    pub audio_hash: Option<String>,
+    #[serde(default)]
+    pub play_count: i64,
+    #[serde(default)]
+    pub rating: Option<i64>,
+    #[serde(default)]
+    pub root_id: Option<i64>,
+}
+
+impl Track {
+    /// Maps a row of a `SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) ...` query,
+    /// by column name. See `TrackColumns` to map many rows.
+    pub fn from_row(row: &Row) -> Result<Self> {
+        TrackColumns::new(row.as_ref())?.read(row)
+    }
+}
+
+/// Columns `TrackColumns` reads, in the order of its indexes
+const TRACK_COLUMNS: [&str; 25] = [
+    "TrackID",
+    "Name",
+    "FullPath",
+    "ArtistID",
+    "AlbumID",
+    "Duration",
+    "LoopStart",
+    "LoopLength",
+    "TrackNumber",
+    "DiscNumber",
+    "Year",
+    "Genre",
+    "AlbumArtist",
+    "Composer",
+    "Comment",
+    "Bitrate",
+    "SampleRate",
+    "FileSize",
+    "MTime",
+    "Missing",
+    "ContentHash",
+    "AudioHash",
+    "PlayCount",
+    "Rating",
+    "RootID",
+];
+
+/// Where the `Track` columns are in the rows of a statement. Looking them up by name
+/// for every row is most of the time spent loading a large playlist.
+pub struct TrackColumns([usize; 25]);
+
+impl TrackColumns {
+    pub fn new(stmt: &Statement) -> Result<Self> {
+        let mut indexes = [0; 25];
+        for (index, name) in indexes.iter_mut().zip(TRACK_COLUMNS) {
+            *index = stmt.column_index(name)?;
+        }
+        Ok(TrackColumns(indexes))
+    }
+
+    pub fn read(&self, row: &Row) -> Result<Track> {
+        let [track_id, name, path, artist_id, album_id, duration, loop_start, loop_length, track_number, disc_number, year, genre, album_artist, composer, comment, bitrate, sample_rate, file_size, mtime, missing, content_hash, audio_hash, play_count, rating, root_id] =
+            self.0;
+        Ok(Track {
+            track_id: row.get(track_id)?,
+            name: row.get(name)?,
+            path: native_path(row.get(path)?),
+            artist_id: row.get(artist_id)?,
+            album_id: row.get(album_id)?,
+            duration: row.get(duration)?,
+            loop_start: row.get(loop_start)?,
+            loop_length: row.get(loop_length)?,
+            track_number: row.get(track_number)?,
+            disc_number: row.get(disc_number)?,
+            year: row.get(year)?,
+            genre: row.get(genre)?,
+            album_artist: row.get(album_artist)?,
+            composer: row.get(composer)?,
+            comment: row.get(comment)?,
+            bitrate: row.get(bitrate)?,
+            sample_rate: row.get(sample_rate)?,
+            file_size: row.get(file_size)?,
+            mtime: row.get(mtime)?,
+            missing: row.get(missing)?,
+            content_hash: row.get(content_hash)?,
+            audio_hash: row.get(audio_hash)?,
+            play_count: row.get(play_count)?,
+            rating: row.get(rating)?,
+            root_id: row.get(root_id)?,
+        })
+    }
 }
 
 /// Artists
 /// - ArtistID (Primary Key)
 /// - Name
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Artist {
     pub artist_id: Option<i32>,
     pub name: String,
@@ -30,11 +154,24 @@ pub struct Artist {
 /// - AlbumID (Primary Key)
 /// - Name
 /// - ArtistID (Foreign Key): Reference to the artist.
-#[derive(Serialize, Deserialize)]
+/// - ArtworkID (Foreign Key): Reference to the artwork, served as `rwave-art://album/<AlbumID>`.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Album {
     pub album_id: Option<i32>,
     pub name: String,
     pub artist_id: Option<i32>,
+    pub artwork_id: Option<i32>,
+}
+
+/// Artworks
+/// - ArtworkID (Primary Key)
+/// - Hash: SHA-256 of the original image, the thumbnail cache key.
+/// - Path: Path of the thumbnail in the app data directory.
+#[derive(Serialize, Deserialize)]
+pub struct Artwork {
+    pub artwork_id: Option<i32>,
+    pub hash: String,
+    pub path: String,
 }
 
 /// Playlists
@@ -45,3 +182,28 @@ pub struct Playlist {
     pub playlist_id: Option<i32>,
     pub name: String,
 }
+
+/// LibraryFolders
+/// - FolderID (Primary Key)
+/// - Path
+/// - IncludePatterns: JSON array of globs, relative to the folder. Empty includes every file.
+/// - ExcludePatterns: JSON array of globs, relative to the folder.
+/// - MinDuration: Shorter files (e.g. sound effects) are not imported, in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LibraryFolder {
+    pub folder_id: Option<i32>,
+    pub path: String,
+    pub include_patterns: Vec<String>,
+    pub exclude_patterns: Vec<String>,
+    pub min_duration: u64,
+}
+
+/// LibraryRoots
+/// - RootID (Primary Key)
+/// - Path: Where the root is on this machine. The paths of its tracks are relative to it,
+///   so the database keeps working once the music is on another drive or computer.
+#[derive(Serialize, Deserialize)]
+pub struct LibraryRoot {
+    pub root_id: Option<i64>,
+    pub path: String,
+}
@@ -0,0 +1,84 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use thiserror::Error;
+
+/// Errors of the library commands. They reach the frontend as
+/// `{ "code": "not_found", "message": "Playlist 3 not found" }`, where `code` is stable
+/// and can be matched on, while `message` is for display only.
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    /// A playlist, track, album or artist that isn't in the library, or a file that isn't on disk
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    /// An argument the library can't use, e.g. a glob that doesn't parse
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The tags of a file can't be read or written
+    #[error("{path}: {message}")]
+    Tag { path: String, message: String },
+    /// The audio of a file can't be probed or decoded
+    #[error("{path}: {message}")]
+    Decode { path: String, message: String },
+    #[error("Database error: {0}")]
+    Db(#[from] rusqlite::Error),
+    #[error("Could not open the database: {0}")]
+    Connection(#[from] r2d2::Error),
+    /// The background job of a command panicked
+    #[error("The job failed: {0}")]
+    Job(#[from] tauri::Error),
+}
+
+impl LibraryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LibraryError::NotFound(_) => "not_found",
+            LibraryError::AlreadyExists(_) => "already_exists",
+            LibraryError::Invalid(_) => "invalid",
+            LibraryError::Io { .. } => "io",
+            LibraryError::Tag { .. } => "tag",
+            LibraryError::Decode { .. } => "decode",
+            LibraryError::Db(_) => "db",
+            LibraryError::Connection(_) => "db_connection",
+            LibraryError::Job(_) => "job",
+        }
+    }
+
+    pub fn tag(path: &str, error: impl std::fmt::Display) -> Self {
+        LibraryError::Tag {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Serialize for LibraryError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut error = serializer.serialize_struct("LibraryError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_code_and_message() {
+        let error = LibraryError::NotFound("Playlist 3".to_string());
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({ "code": "not_found", "message": "Playlist 3 not found" })
+        );
+        let error = LibraryError::from(rusqlite::Error::QueryReturnedNoRows);
+        assert_eq!(serde_json::to_value(&error).unwrap()["code"], "db");
+    }
+}
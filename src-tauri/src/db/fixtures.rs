@@ -0,0 +1,62 @@
+use std::path::Path;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 8000;
+
+/// Writes a tenth of a second of a `frequency` Hz tone as a 16-bit mono WAV file,
+/// tagged with a RIFF INFO chunk. The tests generate their files with it.
+pub fn write_wav(path: &Path, frequency: f32, title: &str, artist: &str, album: &str) {
+    let duration = Duration::from_millis(100);
+    write_wav_lasting(path, duration, frequency, title, artist, album);
+}
+
+/// `write_wav` with `duration` of the tone, for the tests reading the length of the file.
+pub fn write_wav_lasting(
+    path: &Path,
+    duration: Duration,
+    frequency: f32,
+    title: &str,
+    artist: &str,
+    album: &str,
+) {
+    let frames = (duration.as_secs_f64() * SAMPLE_RATE as f64) as u32;
+    let samples: Vec<u8> = (0..frames)
+        .flat_map(|i| {
+            let time = i as f32 / SAMPLE_RATE as f32;
+            let sample = (time * frequency * std::f32::consts::TAU).sin() * 8000.0;
+            (sample as i16).to_le_bytes()
+        })
+        .collect();
+
+    let mut format = Vec::new();
+    format.extend(1u16.to_le_bytes()); // PCM
+    format.extend(1u16.to_le_bytes()); // Channels
+    format.extend(SAMPLE_RATE.to_le_bytes());
+    format.extend((SAMPLE_RATE * 2).to_le_bytes()); // Bytes per second
+    format.extend(2u16.to_le_bytes()); // Bytes per frame
+    format.extend(16u16.to_le_bytes()); // Bits per sample
+
+    let mut info = b"INFO".to_vec();
+    for (id, value) in [(b"INAM", title), (b"IART", artist), (b"IPRD", album)] {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        info.extend(id);
+        info.extend((value.len() as u32).to_le_bytes());
+        // Chunks are word aligned, the padding isn't counted in their size
+        if value.len() % 2 == 1 {
+            value.push(0);
+        }
+        info.extend(value);
+    }
+
+    let mut chunks = b"WAVE".to_vec();
+    for (id, data) in [(b"fmt ", &format), (b"LIST", &info), (b"data", &samples)] {
+        chunks.extend(id);
+        chunks.extend((data.len() as u32).to_le_bytes());
+        chunks.extend(data);
+    }
+    let mut wav = b"RIFF".to_vec();
+    wav.extend((chunks.len() as u32).to_le_bytes());
+    wav.extend(chunks);
+    std::fs::write(path, wav).unwrap();
+}
@@ -0,0 +1,806 @@
+use super::entities::{Album, Artist, Artwork, Track};
+use super::error::LibraryError;
+use super::import::{canonical_path, is_supported_file};
+use super::library::Library;
+use super::pool::DbPool;
+use super::roots::{native_path, store_path};
+use crate::hashing::has_content;
+use crate::jobs::JobRegistry;
+use crate::watcher::LibraryWatcher;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::Mutex;
+use tauri::State;
+use walkdir::WalkDir;
+
+/// Result of `check_health`.
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub checked: usize,
+    /// Tracks whose file is gone, flagged `Missing`
+    pub missing: Vec<Track>,
+    /// Tracks flagged `Missing` whose file is back
+    pub restored: usize,
+}
+
+/// Checks that the file of every track exists, and updates the `Missing` flags.
+pub fn check_health(conn: &mut Connection) -> Result<HealthReport> {
+    let tracks: Vec<(i64, String, bool)> = conn
+        .prepare("SELECT TrackID, FullPath, Missing FROM Tracks JOIN TrackFiles USING (TrackID)")?
+        .query_map([], |row| {
+            Ok((row.get(0)?, native_path(row.get(1)?), row.get(2)?))
+        })?
+        .collect::<Result<_>>()?;
+
+    let tx = conn.transaction()?;
+    let mut missing_ids = Vec::new();
+    let mut restored = 0;
+    for (track_id, path, missing) in &tracks {
+        let exists = Path::new(path).is_file();
+        if exists == *missing {
+            tx.execute(
+                "UPDATE Tracks SET Missing = ?1 WHERE TrackID = ?2",
+                params![!exists, track_id],
+            )?;
+        }
+        if !exists {
+            missing_ids.push(*track_id);
+        } else if *missing {
+            restored += 1;
+        }
+    }
+
+    let missing = {
+        let mut stmt =
+            tx.prepare("SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) WHERE TrackID = ?")?;
+        missing_ids
+            .iter()
+            .map(|track_id| stmt.query_row(params![track_id], Track::from_row))
+            .collect::<Result<Vec<_>>>()?
+    };
+    tx.commit()?;
+
+    Ok(HealthReport {
+        checked: tracks.len(),
+        missing,
+        restored,
+    })
+}
+
+/// How `relocate_missing` found the new file of a track.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelinkMethod {
+    /// The end of the old path, e.g. `Artist/Album/01.mp3`, exists under the new root
+    RelativePath,
+    /// The only file under the new root with this name
+    Filename,
+    /// A file under the new root with the same size and content hash
+    ContentHash,
+}
+
+#[derive(Serialize)]
+pub struct Relink {
+    pub track_id: i64,
+    pub old_path: String,
+    pub new_path: String,
+    pub method: RelinkMethod,
+}
+
+/// Result of `relocate_missing`.
+#[derive(Serialize)]
+pub struct RelocationReport {
+    pub relinked: Vec<Relink>,
+    /// Missing tracks with no match under the new root
+    pub unresolved: Vec<i64>,
+    /// Whether the relinks were only proposed, not applied
+    pub dry_run: bool,
+}
+
+/// The audio files under a folder, by lowercase file name and by size.
+struct FileIndex {
+    by_name: HashMap<String, Vec<PathBuf>>,
+    by_size: HashMap<u64, Vec<PathBuf>>,
+}
+
+impl FileIndex {
+    fn build(root: &Path) -> Self {
+        let mut index = FileIndex {
+            by_name: HashMap::new(),
+            by_size: HashMap::new(),
+        };
+        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() || !is_supported_file(entry.path()) {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_lowercase();
+            index
+                .by_name
+                .entry(name)
+                .or_default()
+                .push(entry.path().to_path_buf());
+            if let Ok(metadata) = entry.metadata() {
+                index
+                    .by_size
+                    .entry(metadata.len())
+                    .or_default()
+                    .push(entry.path().to_path_buf());
+            }
+        }
+        index
+    }
+}
+
+/// Looks for the new file of a missing track under `root`: by the longest end of its
+/// old path that exists there, then by its file name if that name is unique and the file
+/// has the size (and content, when its hash is known) of the track, then by content
+/// among the files of the same size. See `has_content` for `hash` and `audio`.
+fn find_new_path(
+    root: &Path,
+    index: &FileIndex,
+    old_path: &str,
+    size: Option<i64>,
+    hash: Option<&str>,
+    audio: Option<&str>,
+) -> Option<(PathBuf, RelinkMethod)> {
+    // Without the root and the drive, which would replace `root` when joined
+    let components: Vec<_> = Path::new(old_path)
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    for start in 0..components.len() {
+        let candidate = components[start..]
+            .iter()
+            .fold(root.to_path_buf(), |path, component| path.join(component));
+        if candidate.is_file() {
+            return Some((candidate, RelinkMethod::RelativePath));
+        }
+    }
+
+    let name = Path::new(old_path)
+        .file_name()?
+        .to_string_lossy()
+        .to_lowercase();
+    // Another file may have the same name, its size and content must match too
+    if let (Some([candidate]), Some(size)) = (index.by_name.get(&name).map(Vec::as_slice), size) {
+        let same_size = std::fs::metadata(candidate).is_ok_and(|m| m.len() == size as u64);
+        let same_content = hash.map_or(true, |hash| {
+            has_content(&candidate.to_string_lossy(), hash, audio)
+        });
+        if same_size && same_content {
+            return Some((candidate.clone(), RelinkMethod::Filename));
+        }
+    }
+
+    let hash = hash?;
+    let candidates = index.by_size.get(&(size? as u64))?;
+    candidates
+        .iter()
+        .find(|candidate| has_content(&candidate.to_string_lossy(), hash, audio))
+        .map(|candidate| (candidate.clone(), RelinkMethod::ContentHash))
+}
+
+/// The id, old path, size, content hash and audio hash of a missing track.
+type MissingTrack = (i64, String, Option<i64>, Option<String>, Option<String>);
+
+/// Re-links the missing tracks to their files under `new_root`, e.g. after the music
+/// was moved to another folder or drive. A file already used by a track is never taken.
+/// With `dry_run`, only reports what would be re-linked.
+pub fn relocate_missing(
+    conn: &mut Connection,
+    new_root: &str,
+    dry_run: bool,
+) -> Result<RelocationReport> {
+    let root = PathBuf::from(canonical_path(Path::new(new_root)));
+    let index = FileIndex::build(&root);
+
+    let missing: Vec<MissingTrack> = conn
+        .prepare(
+            "SELECT TrackID, FullPath, FileSize, ContentHash, AudioHash FROM Tracks
+            JOIN TrackFiles USING (TrackID) WHERE Missing = 1",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                native_path(row.get(1)?),
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?
+        .collect::<Result<_>>()?;
+
+    let tx = conn.transaction()?;
+    let mut relinked = Vec::new();
+    let mut unresolved = Vec::new();
+    for (track_id, old_path, size, hash, audio) in missing {
+        let found = find_new_path(
+            &root,
+            &index,
+            &old_path,
+            size,
+            hash.as_deref(),
+            audio.as_deref(),
+        )
+        .map(|(path, method)| (canonical_path(&path), method));
+        let Some((new_path, method)) = found else {
+            unresolved.push(track_id);
+            continue;
+        };
+
+        // Two missing tracks may match the same file, the first one wins
+        let (root_id, stored) = store_path(&tx, &new_path)?;
+        let taken: Option<i64> = tx
+            .query_row(
+                "SELECT TrackID FROM Tracks WHERE Path = ?1 AND RootID IS ?2",
+                params![stored, root_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if taken.is_some() {
+            unresolved.push(track_id);
+            continue;
+        }
+
+        tx.execute(
+            "UPDATE Tracks SET RootID = ?1, Path = ?2, Missing = 0 WHERE TrackID = ?3",
+            params![root_id, stored, track_id],
+        )?;
+        relinked.push(Relink {
+            track_id,
+            old_path,
+            new_path,
+            method,
+        });
+    }
+
+    // The updates above are still needed to keep a file from being matched twice
+    if dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    Ok(RelocationReport {
+        relinked,
+        unresolved,
+        dry_run,
+    })
+}
+
+/// `prefix` ending with exactly one separator, so `/music` doesn't match `/music2/...`.
+fn directory_prefix(prefix: &str) -> String {
+    format!(
+        "{}{}",
+        prefix.trim_end_matches(MAIN_SEPARATOR),
+        MAIN_SEPARATOR
+    )
+}
+
+/// Result of `rewrite_prefix`.
+#[derive(Serialize)]
+pub struct PrefixRewrite {
+    /// Tracks outside the library roots, stored with their absolute path
+    pub tracks: usize,
+    pub roots: usize,
+    pub folders: usize,
+    /// Tracks under the new prefix whose file doesn't exist
+    pub still_missing: usize,
+    pub dry_run: bool,
+}
+
+/// Replaces `old_prefix` with `new_prefix` in the paths of the library roots and folders,
+/// and of the tracks outside the roots, e.g. `D:\Music` to `E:\Music` after a drive
+/// was remounted elsewhere.
+/// With `dry_run`, only reports what would be rewritten.
+pub fn rewrite_prefix(
+    conn: &mut Connection,
+    old_prefix: &str,
+    new_prefix: &str,
+    dry_run: bool,
+) -> Result<PrefixRewrite> {
+    let old_dir = directory_prefix(old_prefix);
+    let new_dir = directory_prefix(new_prefix);
+
+    let tx = conn.transaction()?;
+    let tracks = tx.execute(
+        "UPDATE Tracks SET Path = ?2 || substr(Path, length(?1) + 1)
+        WHERE RootID IS NULL AND substr(Path, 1, length(?1)) = ?1",
+        params![old_dir, new_dir],
+    )?;
+    let directories = params![
+        old_prefix.trim_end_matches(MAIN_SEPARATOR),
+        old_dir,
+        new_prefix.trim_end_matches(MAIN_SEPARATOR),
+        new_dir
+    ];
+    let roots = tx.execute(
+        "UPDATE LibraryRoots SET Path = CASE
+            WHEN Path = ?1 OR Path = ?2 THEN ?3
+            ELSE ?4 || substr(Path, length(?2) + 1)
+        END
+        WHERE Path = ?1 OR Path = ?2 OR substr(Path, 1, length(?2)) = ?2",
+        directories,
+    )?;
+    let folders = tx.execute(
+        "UPDATE LibraryFolders SET Path = CASE
+            WHEN Path = ?1 OR Path = ?2 THEN ?3
+            ELSE ?4 || substr(Path, length(?2) + 1)
+        END
+        WHERE Path = ?1 OR Path = ?2 OR substr(Path, 1, length(?2)) = ?2",
+        directories,
+    )?;
+
+    // `FullPath` is joined with `/`, compared once back to the separators of the platform
+    let resolved: Vec<(i64, String)> = tx
+        .prepare("SELECT TrackID, FullPath FROM Tracks JOIN TrackFiles USING (TrackID)")?
+        .query_map([], |row| Ok((row.get(0)?, native_path(row.get(1)?))))?
+        .collect::<Result<_>>()?;
+    let rewritten = resolved
+        .into_iter()
+        .filter(|(_, path)| path.starts_with(&new_dir));
+    let mut still_missing = 0;
+    for (track_id, path) in rewritten {
+        let missing = !Path::new(&path).is_file();
+        still_missing += missing as usize;
+        tx.execute(
+            "UPDATE Tracks SET Missing = ?1 WHERE TrackID = ?2",
+            params![missing, track_id],
+        )?;
+    }
+
+    if dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    Ok(PrefixRewrite {
+        tracks,
+        roots,
+        folders,
+        still_missing,
+        dry_run,
+    })
+}
+
+/// Result of `vacuum`.
+#[derive(Serialize)]
+pub struct VacuumReport {
+    /// Albums without tracks
+    pub albums: Vec<Album>,
+    /// Artists without tracks or albums
+    pub artists: Vec<Artist>,
+    /// Artworks of no album, their thumbnails are deleted from the cache
+    pub artworks: Vec<Artwork>,
+}
+
+/// Deletes the albums and artists no track refers to anymore, e.g. after their tracks
+/// were merged or deleted, then the artworks no album uses and their thumbnails.
+pub fn vacuum(conn: &mut Connection) -> Result<VacuumReport> {
+    let tx = conn.transaction()?;
+
+    let albums: Vec<Album> = tx
+        .prepare(
+            "SELECT AlbumID, Name, ArtistID, ArtworkID FROM Albums
+            WHERE NOT EXISTS (SELECT 1 FROM Tracks WHERE Tracks.AlbumID = Albums.AlbumID)",
+        )?
+        .query_map([], |row| {
+            Ok(Album {
+                album_id: row.get(0)?,
+                name: row.get(1)?,
+                artist_id: row.get(2)?,
+                artwork_id: row.get(3)?,
+            })
+        })?
+        .collect::<Result<_>>()?;
+    for album in &albums {
+        tx.execute(
+            "DELETE FROM Albums WHERE AlbumID = ?",
+            params![album.album_id],
+        )?;
+    }
+
+    let artists: Vec<Artist> = tx
+        .prepare(
+            "SELECT ArtistID, Name FROM Artists
+            WHERE NOT EXISTS (SELECT 1 FROM Tracks WHERE Tracks.ArtistID = Artists.ArtistID)
+            AND NOT EXISTS (SELECT 1 FROM Albums WHERE Albums.ArtistID = Artists.ArtistID)",
+        )?
+        .query_map([], |row| {
+            Ok(Artist {
+                artist_id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_>>()?;
+    for artist in &artists {
+        tx.execute(
+            "DELETE FROM Artists WHERE ArtistID = ?",
+            params![artist.artist_id],
+        )?;
+    }
+
+    let artworks: Vec<Artwork> = tx
+        .prepare(
+            "SELECT ArtworkID, Hash, Path FROM Artworks
+            WHERE NOT EXISTS (SELECT 1 FROM Albums WHERE Albums.ArtworkID = Artworks.ArtworkID)",
+        )?
+        .query_map([], |row| {
+            Ok(Artwork {
+                artwork_id: row.get(0)?,
+                hash: row.get(1)?,
+                path: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_>>()?;
+    for artwork in &artworks {
+        tx.execute(
+            "DELETE FROM Artworks WHERE ArtworkID = ?",
+            params![artwork.artwork_id],
+        )?;
+    }
+
+    tx.commit()?;
+
+    // Only once the library no longer references them
+    for artwork in &artworks {
+        if let Err(e) = std::fs::remove_file(&artwork.path) {
+            log::warn!("Could not delete the thumbnail {}: {}", artwork.path, e);
+        }
+    }
+
+    Ok(VacuumReport {
+        albums,
+        artists,
+        artworks,
+    })
+}
+
+/// Check that the file of every track exists, flagging the missing ones.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn check_library_health(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<HealthReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("check_library_health", None, move |_| {
+        Library::from_pool(&pool)?.check_health()
+    })
+    .await?
+}
+
+/// Re-link the missing tracks to their files under `new_root`, by relative path,
+/// file name or content hash. Run `check_library_health` first to flag them.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn relocate_missing_tracks(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    new_root: String,
+    dry_run: Option<bool>,
+) -> Result<RelocationReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("relocate_missing_tracks", None, move |_| {
+        Library::from_pool(&pool)?.relocate_missing(&new_root, dry_run.unwrap_or(false))
+    })
+    .await?
+}
+
+/// Rewrite the start of the library root, folder and track paths, for a music drive
+/// mounted somewhere else. The watched folders follow.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn rewrite_path_prefix(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    old_prefix: String,
+    new_prefix: String,
+    dry_run: Option<bool>,
+) -> Result<PrefixRewrite, LibraryError> {
+    let dry_run = dry_run.unwrap_or(false);
+    let pool = db.inner().clone();
+    let (rewrite, old_folders, new_folders) = jobs
+        .run("rewrite_path_prefix", None, move |_| {
+            let mut library = Library::from_pool(&pool)?;
+            let old_folders = library.folders()?;
+            let rewrite = library.rewrite_prefix(&old_prefix, &new_prefix, dry_run)?;
+            let new_folders = library.folders()?;
+            Ok::<_, LibraryError>((rewrite, old_folders, new_folders))
+        })
+        .await??;
+
+    if !dry_run && rewrite.folders > 0 {
+        let mut watcher = watcher.lock().unwrap();
+        for folder in old_folders {
+            watcher.unwatch(&folder.path);
+        }
+        for folder in new_folders {
+            watcher.watch(&folder.path);
+        }
+    }
+
+    Ok(rewrite)
+}
+
+/// Delete the albums and artists left without tracks, and the artworks left
+/// without albums, returning them.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn vacuum_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<VacuumReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("vacuum_library", None, move |_| {
+        Library::from_pool(&pool)?.vacuum()
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::write_wav;
+    use crate::db::import::ImportOutcome;
+    use crate::db::libraryfolders::load_folders;
+    use crate::hashing::content_hash;
+
+    /// A temporary folder for the test, with `files` written as fixtures.
+    fn folder(test: &str, files: &[&str]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rwave-health-{}-{}", test, std::process::id()));
+        for (i, file) in files.iter().enumerate() {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            write_wav(&path, 220.0 * (i + 1) as f32, file, "Artist", "Album");
+        }
+        PathBuf::from(canonical_path(&dir))
+    }
+
+    fn import(library: &mut Library, path: &Path) -> i64 {
+        match library.import_track(&path.to_string_lossy()).unwrap() {
+            ImportOutcome::Created(track_id) => track_id,
+            _ => panic!("The track wasn't created"),
+        }
+    }
+
+    fn track_path(conn: &Connection, track_id: i64) -> String {
+        conn.query_row(
+            "SELECT FullPath FROM TrackFiles WHERE TrackID = ?",
+            params![track_id],
+            |row| row.get(0).map(native_path),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_find_new_path() {
+        let root = folder(
+            "find",
+            &[
+                "Artist/Album/01.wav",
+                "Singles/Solo.wav",
+                "Renamed/track.wav",
+                "a/dup.wav",
+                "b/dup.wav",
+            ],
+        );
+        let index = FileIndex::build(&root);
+        let renamed = root.join("Renamed/track.wav");
+        let size = std::fs::metadata(&renamed).unwrap().len() as i64;
+        let hash = content_hash(&renamed.to_string_lossy()).unwrap();
+        let old = |path: &str| {
+            let old_drive = std::env::temp_dir().join("rwave-old-drive");
+            old_drive.join(path).to_string_lossy().to_string()
+        };
+
+        // The longest end of the old path that exists under the root
+        let found = find_new_path(
+            &root,
+            &index,
+            &old("Music/Artist/Album/01.wav"),
+            None,
+            None,
+            None,
+        );
+        assert!(matches!(
+            found,
+            Some((path, RelinkMethod::RelativePath)) if path == root.join("Artist/Album/01.wav")
+        ));
+        // A unique file name, whatever its case, with the size and content of the track
+        let solo = root.join("Singles/Solo.wav");
+        let solo_size = std::fs::metadata(&solo).unwrap().len() as i64;
+        let solo_hash = content_hash(&solo.to_string_lossy()).unwrap();
+        let found = find_new_path(
+            &root,
+            &index,
+            &old("Other/solo.WAV"),
+            Some(solo_size),
+            None,
+            None,
+        );
+        assert!(matches!(
+            found,
+            Some((path, RelinkMethod::Filename)) if path == solo
+        ));
+        let found = find_new_path(
+            &root,
+            &index,
+            &old("Other/solo.WAV"),
+            Some(solo_size),
+            Some(&solo_hash),
+            None,
+        );
+        assert!(matches!(found, Some((_, RelinkMethod::Filename))));
+        // The same name on another file
+        let other =
+            |size, hash| find_new_path(&root, &index, &old("Other/solo.WAV"), size, hash, None);
+        assert!(other(None, None).is_none());
+        assert!(other(Some(solo_size + 1), None).is_none());
+        assert!(other(Some(solo_size), Some("0")).is_none());
+        // The content hash among the files of the same size
+        let found = find_new_path(
+            &root,
+            &index,
+            &old("lost.wav"),
+            Some(size),
+            Some(&hash),
+            None,
+        );
+        assert!(matches!(
+            found,
+            Some((path, RelinkMethod::ContentHash)) if path == renamed
+        ));
+        // A name shared by two files, without a hash to tell them apart
+        assert!(find_new_path(&root, &index, &old("c/dup.wav"), Some(size), None, None).is_none());
+        assert!(
+            find_new_path(&root, &index, &old("lost.wav"), Some(size), Some("0"), None).is_none()
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_relocate_missing() {
+        // Two copies of a file, moved to a folder that holds only one
+        let old = folder("relocate-old", &["x/song.wav"]);
+        std::fs::create_dir_all(old.join("y")).unwrap();
+        std::fs::copy(old.join("x/song.wav"), old.join("y/song.wav")).unwrap();
+        let mut library = Library::open(":memory:").unwrap();
+        let first = import(&mut library, &old.join("x/song.wav"));
+        let second = import(&mut library, &old.join("y/song.wav"));
+        let new = folder("relocate-new", &[]);
+        std::fs::create_dir_all(&new).unwrap();
+        std::fs::copy(old.join("x/song.wav"), new.join("song.wav")).unwrap();
+        std::fs::remove_dir_all(&old).unwrap();
+
+        let conn = library.connection();
+        assert_eq!(check_health(conn).unwrap().missing.len(), 2);
+
+        // Both tracks match the one file, only the first one gets it
+        let report = relocate_missing(conn, &new.to_string_lossy(), true).unwrap();
+        let relinked: Vec<i64> = report.relinked.iter().map(|r| r.track_id).collect();
+        assert_eq!(relinked, [first]);
+        assert_eq!(report.unresolved, [second]);
+        // Nothing changed with a dry run
+        assert_eq!(
+            track_path(conn, first),
+            old.join("x/song.wav").to_string_lossy()
+        );
+        assert_eq!(check_health(conn).unwrap().missing.len(), 2);
+
+        let report = relocate_missing(conn, &new.to_string_lossy(), false).unwrap();
+        assert_eq!(report.relinked.len(), 1);
+        assert_eq!(
+            track_path(conn, first),
+            new.join("song.wav").to_string_lossy()
+        );
+        let health = check_health(conn).unwrap();
+        let missing: Vec<Option<i32>> = health.missing.iter().map(|t| t.track_id).collect();
+        assert_eq!(missing, [Some(second as i32)]);
+
+        std::fs::remove_dir_all(new).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_prefix() {
+        let dir = folder("rewrite", &["new/a.wav"]);
+        let (old_dir, new_dir) = (dir.join("old"), dir.join("new"));
+        let path = |base: &Path, file: &str| base.join(file).to_string_lossy().to_string();
+
+        let mut library = Library::open(":memory:").unwrap();
+        let conn = library.connection();
+        conn.execute(
+            "INSERT INTO LibraryRoots (Path) VALUES (?1), (?2)",
+            params![path(&old_dir, "Rock"), path(&dir, "old2")],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO LibraryFolders (Path, IncludePatterns, ExcludePatterns, MinDuration)
+            VALUES (?, '[]', '[]', 0)",
+            params![path(&old_dir, "Rock")],
+        )
+        .unwrap();
+        conn.execute_batch(
+            "INSERT INTO Artists (Name) VALUES ('Artist');
+            INSERT INTO Albums (Name, ArtistID) VALUES ('Album', 1);",
+        )
+        .unwrap();
+        for file in [path(&old_dir, "a.wav"), path(&old_dir, "b.wav")] {
+            conn.execute(
+                "INSERT INTO Tracks (Name, Path, ArtistID, AlbumID) VALUES ('Track', ?, 1, 1)",
+                params![file],
+            )
+            .unwrap();
+        }
+
+        let old_prefix = old_dir.to_string_lossy().to_string();
+        let new_prefix = new_dir.to_string_lossy().to_string();
+        let rewrite = rewrite_prefix(conn, &old_prefix, &new_prefix, true).unwrap();
+        assert_eq!((rewrite.tracks, rewrite.roots, rewrite.folders), (2, 1, 1));
+        // Only b.wav isn't under the new prefix
+        assert_eq!(rewrite.still_missing, 1);
+        assert_eq!(track_path(conn, 1), path(&old_dir, "a.wav"));
+
+        rewrite_prefix(conn, &old_prefix, &new_prefix, false).unwrap();
+        assert_eq!(track_path(conn, 1), path(&new_dir, "a.wav"));
+        let roots: Vec<String> = conn
+            .prepare("SELECT Path FROM LibraryRoots ORDER BY RootID")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        // `old2` only shares the start of its name with the prefix
+        assert_eq!(roots, [path(&new_dir, "Rock"), path(&dir, "old2")]);
+        let folders = load_folders(conn).unwrap();
+        assert_eq!(folders[0].path, path(&new_dir, "Rock"));
+        let missing: Vec<bool> = conn
+            .prepare("SELECT Missing FROM Tracks ORDER BY TrackID")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(missing, [false, true]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_vacuum_deletes_unused_artworks() {
+        let dir = folder("vacuum", &["a.wav"]);
+        let mut library = Library::open(":memory:").unwrap();
+        let track_id = import(&mut library, &dir.join("a.wav"));
+        let conn = library.connection();
+        let thumbnail = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, b"jpeg").unwrap();
+            path.to_string_lossy().to_string()
+        };
+        conn.execute(
+            "INSERT INTO Artworks (ArtworkID, Hash, Path) VALUES (1, 'used', ?1), (2, 'unused', ?2)",
+            params![thumbnail("used.jpg"), thumbnail("unused.jpg")],
+        )
+        .unwrap();
+        conn.execute("UPDATE Albums SET ArtworkID = 1", ()).unwrap();
+
+        let report = vacuum(conn).unwrap();
+        let hashes: Vec<String> = report.artworks.into_iter().map(|a| a.hash).collect();
+        assert_eq!(hashes, ["unused"]);
+        assert!(dir.join("used.jpg").exists());
+        assert!(!dir.join("unused.jpg").exists());
+
+        // Once its album is gone, the artwork goes too
+        conn.execute("DELETE FROM Tracks WHERE TrackID = ?", params![track_id])
+            .unwrap();
+        let report = vacuum(conn).unwrap();
+        assert_eq!(report.albums.len(), 1);
+        let hashes: Vec<String> = report.artworks.into_iter().map(|a| a.hash).collect();
+        assert_eq!(hashes, ["used"]);
+        assert!(!dir.join("used.jpg").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+use super::error::LibraryError;
+use super::roots::{register_root, store_path};
+use crate::hashing::{audio_hash, content_hash, has_content};
+use crate::metadata::{read_track_metadata, TrackMetadata};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use walkdir::WalkDir;
+
+/// What `import_track` did with a file.
+#[derive(Debug)]
+pub enum ImportOutcome {
+    /// The track was inserted, with its new `TrackID`
+    Created(i64),
+    /// A track whose file is gone had the same content, it now points to the new path
+    Relocated(i64),
+    /// A track already points to this file
+    AlreadyExists,
+}
+
+/// The absolute path of a file with symlinks, `.` and `..` resolved, which identifies
+/// its track. Plain (not `\\?\`) paths on Windows. Falls back to `path` for a missing file.
+pub fn canonical_path(path: &Path) -> String {
+    dunce::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Returns the id of the artist named `name`, inserting it if needed.
+fn artist_id(conn: &Connection, name: &str) -> Result<i64> {
+    match conn.query_row(
+        "SELECT ArtistID FROM Artists WHERE Name = ?",
+        params![name],
+        |row| row.get(0),
+    ) {
+        Ok(id) => Ok(id),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            conn.execute("INSERT INTO Artists (Name) VALUES (?)", params![name])?;
+            Ok(conn.last_insert_rowid())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the id of the album named `name` by `artist_id`, inserting it if needed.
+fn album_id(conn: &Connection, name: &str, artist_id: i64) -> Result<i64> {
+    match conn.query_row(
+        "SELECT AlbumID FROM Albums WHERE Name = ? AND ArtistID = ?",
+        params![name, artist_id],
+        |row| row.get(0),
+    ) {
+        Ok(id) => Ok(id),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            conn.execute(
+                "INSERT INTO Albums (Name, ArtistID) VALUES (?, ?)",
+                params![name, artist_id],
+            )?;
+            Ok(conn.last_insert_rowid())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the tag and file properties columns of a track, everything but its name,
+/// artist and album.
+pub fn update_track_details(
+    conn: &Connection,
+    track_id: i64,
+    metadata: &TrackMetadata,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE Tracks SET Duration = COALESCE(?1, Duration), TrackNumber = ?2,
+            DiscNumber = ?3, Year = ?4, Genre = ?5, AlbumArtist = ?6, Composer = ?7,
+            Comment = ?8, Bitrate = ?9, SampleRate = ?10, FileSize = ?11, MTime = ?12
+        WHERE TrackID = ?13",
+        params![
+            metadata.duration,
+            metadata.track_number,
+            metadata.disc_number,
+            metadata.year,
+            metadata.genre,
+            metadata.album_artist,
+            metadata.composer,
+            metadata.comment,
+            metadata.bitrate,
+            metadata.sample_rate,
+            metadata.file_size,
+            metadata.mtime,
+            track_id,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Stores the content hash of the file at `path`, after the file was written or replaced.
+pub fn update_content_hash(conn: &Connection, track_id: i64, path: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE Tracks SET ContentHash = ?1 WHERE TrackID = ?2",
+        params![content_hash(path).ok(), track_id],
+    )?;
+    Ok(())
+}
+
+/// Points the track that had the content `hash` to `path`, if its own file is gone.
+/// Several tracks may share a hash (copies of a file), only a missing one is moved.
+/// A sampled hash is confirmed by the audio hash of the track, see `has_content`.
+pub fn relocate_track(conn: &Connection, path: &str, hash: &str) -> Result<Option<i64>> {
+    let candidates: Vec<(i64, String, Option<String>)> = conn
+        .prepare_cached(
+            "SELECT TrackID, FullPath, AudioHash FROM Tracks JOIN TrackFiles USING (TrackID)
+            WHERE ContentHash = ?",
+        )?
+        .query_map(params![hash], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<_>>()?;
+
+    match candidates.into_iter().find(|(_, old_path, audio)| {
+        !Path::new(old_path).exists() && has_content(path, hash, audio.as_deref())
+    }) {
+        Some((track_id, _, _)) => {
+            let (root_id, stored) = store_path(conn, path)?;
+            conn.execute(
+                "UPDATE Tracks SET RootID = ?1, Path = ?2, Missing = 0 WHERE TrackID = ?3",
+                params![root_id, stored, track_id],
+            )?;
+            Ok(Some(track_id))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The name, artist id and album id of a track with the tags `metadata`,
+/// adding the artist and album if needed.
+fn track_identity<'a>(
+    conn: &Connection,
+    metadata: &'a TrackMetadata,
+) -> Result<(&'a str, i64, i64)> {
+    let track_name = metadata.title.as_deref().unwrap_or("Unknown Title");
+    let artist_name = metadata.artist.as_deref().unwrap_or("Unknown Artist");
+    let album_name = metadata.album.as_deref().unwrap_or("Unknown Album");
+
+    let artist_id = artist_id(conn, artist_name)?;
+    let album_id = album_id(conn, album_name, artist_id)?;
+    Ok((track_name, artist_id, album_id))
+}
+
+/// Writes the name, artist and album of a track, adding the artist and album if needed.
+pub fn update_track_identity(
+    conn: &Connection,
+    track_id: i64,
+    metadata: &TrackMetadata,
+) -> Result<()> {
+    let (track_name, artist_id, album_id) = track_identity(conn, metadata)?;
+    conn.execute(
+        "UPDATE Tracks SET Name = ?1, ArtistID = ?2, AlbumID = ?3 WHERE TrackID = ?4",
+        params![track_name, artist_id, album_id, track_id],
+    )?;
+    Ok(())
+}
+
+/// Adds the track at `path` with the given tags, its album and artist (if not already
+/// in the database), and adds it to `All Tracks`.
+/// A track is identified by its canonical path, and by its content hash once its file
+/// moved: tracks that only share a title, like two different "Intro"s, are kept apart.
+/// The path is stored relative to the library root containing it, see `roots`.
+/// Meant to run inside a transaction, see `import_track`.
+pub fn insert_track(
+    conn: &Connection,
+    path: &str,
+    metadata: &TrackMetadata,
+) -> Result<ImportOutcome> {
+    let path = &canonical_path(Path::new(path));
+    let (root_id, stored) = store_path(conn, path)?;
+    let existing: Option<i64> = conn
+        .prepare_cached("SELECT TrackID FROM Tracks WHERE Path = ?1 AND RootID IS ?2")?
+        .query_row(params![stored, root_id], |row| row.get(0))
+        .optional()?;
+    if existing.is_some() {
+        return Ok(ImportOutcome::AlreadyExists);
+    }
+
+    let hash = content_hash(path).ok();
+    if let Some(hash) = &hash {
+        if let Some(track_id) = relocate_track(conn, path, hash)? {
+            return Ok(ImportOutcome::Relocated(track_id));
+        }
+    }
+
+    let (track_name, artist_id, album_id) = track_identity(conn, metadata)?;
+    let loop_points = crate::looping::read_loop_points(path);
+    conn.execute(
+        "INSERT INTO Tracks (Name, RootID, Path, ArtistID, AlbumID, Duration, LoopStart, LoopLength, ContentHash, AudioHash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            track_name,
+            root_id,
+            stored,
+            artist_id,
+            album_id,
+            metadata.duration.unwrap_or(0),
+            loop_points.map(|p| p.start as i64),
+            loop_points.map(|p| p.length as i64),
+            hash,
+            // Confirms the content hash if the file moves, see `relocate_track`
+            audio_hash(path)
+        ],
+    )?;
+    let track_id = conn.last_insert_rowid();
+    update_track_details(conn, track_id, metadata)?;
+
+    // A missing or unreadable picture doesn't prevent the import
+    if let Err(e) = crate::artwork::store_album_artwork(conn, album_id, path) {
+        log::warn!("Could not read the artwork of {}: {}", path, e);
+    }
+
+    // Add the track to the playlist "All Tracks"
+    conn.execute(
+        "INSERT INTO TrackPlaylist (TrackID, PlaylistID) VALUES (?1, ?2)",
+        params![track_id, 1],
+    )?;
+
+    Ok(ImportOutcome::Created(track_id))
+}
+
+/// Updates a track already in the database from the tags read again from its file.
+pub fn refresh_track(
+    conn: &Connection,
+    track_id: i64,
+    path: &str,
+    metadata: &TrackMetadata,
+) -> Result<()> {
+    update_track_identity(conn, track_id, metadata)?;
+    update_track_details(conn, track_id, metadata)?;
+    update_content_hash(conn, track_id, path)?;
+    let loop_points = crate::looping::read_loop_points(path);
+    conn.execute(
+        "UPDATE Tracks SET LoopStart = ?1, LoopLength = ?2 WHERE TrackID = ?3",
+        params![
+            loop_points.map(|p| p.start as i64),
+            loop_points.map(|p| p.length as i64),
+            track_id
+        ],
+    )?;
+    // The audio may have changed too, `duplicates` fingerprints it again
+    conn.execute(
+        "UPDATE Tracks SET AudioHash = ?1, Fingerprint = NULL WHERE TrackID = ?2",
+        params![audio_hash(path), track_id],
+    )?;
+    Ok(())
+}
+
+/// Parses the tags of the file at `path`, adds its album and artist (if not already
+/// in the database), then the track itself, and finally adds it to `All Tracks`.
+/// A file whose tags can't be read isn't added.
+pub fn import_track(
+    conn: &mut Connection,
+    path: &str,
+) -> std::result::Result<ImportOutcome, LibraryError> {
+    // Parse the tags, whatever the format of the file
+    let metadata = read_track_metadata(path).map_err(|e| LibraryError::tag(path, e))?;
+
+    // Begin a transaction, for this series of operations,
+    // it is rolled back when dropped on an error
+    let tx = conn.transaction()?;
+    let outcome = insert_track(&tx, path, &metadata)?;
+    tx.commit()?;
+
+    Ok(outcome)
+}
+
+/// Extensions of the files `import_folder` picks up, the formats the player can decode
+pub const SUPPORTED_EXTENSIONS: [&str; 15] = [
+    "mp3", "flac", "m4a", "mp4", "aac", "ogg", "oga", "opus", "wav", "aif", "aiff", "ape", "wv",
+    "mid", "midi",
+];
+
+pub fn is_supported_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Files written per transaction by the jobs going over the whole library, so other
+/// connections aren't locked out until the end and a crash only loses the last batch.
+pub const BATCH_SIZE: usize = 100;
+
+/// Options of `import_folder`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// Walk the subdirectories too
+    pub recursive: bool,
+    pub follow_links: bool,
+    /// Files imported per transaction, progress is reported after each batch
+    pub batch_size: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            recursive: true,
+            follow_links: false,
+            batch_size: BATCH_SIZE,
+        }
+    }
+}
+
+/// Counters of a running import, sent after each batch.
+#[derive(Clone, Default, Serialize)]
+pub struct ImportProgress {
+    /// Audio files found so far
+    pub scanned: usize,
+    pub added: usize,
+    /// Files already in the library
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportFailure {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Result of `import_folder`.
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub progress: ImportProgress,
+    pub failures: Vec<ImportFailure>,
+    /// Whether the import was stopped before the whole folder was walked,
+    /// the tracks imported until then are kept
+    pub cancelled: bool,
+}
+
+/// Walks `root` and imports every supported audio file, `batch_size` files per transaction.
+/// A file that fails is reported and doesn't undo the rest of its batch.
+/// `cancel` is checked between files. `root` becomes a library root, see `roots`.
+pub fn import_folder<F>(
+    conn: &mut Connection,
+    root: &str,
+    options: &ImportOptions,
+    cancel: &AtomicBool,
+    mut on_progress: F,
+) -> Result<ImportReport>
+where
+    F: FnMut(&ImportProgress),
+{
+    let mut progress = ImportProgress::default();
+    let mut failures = Vec::new();
+    let mut cancelled = false;
+
+    let walker = WalkDir::new(root)
+        .follow_links(options.follow_links)
+        .max_depth(if options.recursive { usize::MAX } else { 1 });
+
+    let mut tx = conn.transaction()?;
+    if Path::new(root).is_dir() {
+        register_root(&tx, &canonical_path(Path::new(root)))?;
+    }
+    let mut batch = 0;
+    for entry in walker {
+        if cancel.load(Ordering::Relaxed) {
+            cancelled = true;
+            break;
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(Path::new(root));
+                failures.push(ImportFailure {
+                    path: path.to_string_lossy().to_string(),
+                    reason: e.to_string(),
+                });
+                progress.failed += 1;
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() || !is_supported_file(entry.path()) {
+            continue;
+        }
+
+        progress.scanned += 1;
+        let path = entry.path().to_string_lossy().to_string();
+        let outcome = match read_track_metadata(&path) {
+            Ok(metadata) => {
+                // Each file gets a savepoint, so a failing insert only undoes its own rows
+                let savepoint = tx.savepoint()?;
+                insert_track(&savepoint, &path, &metadata)
+                    .and_then(|outcome| savepoint.commit().map(|_| outcome))
+                    .map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+
+        match outcome {
+            Ok(ImportOutcome::Created(_)) => progress.added += 1,
+            Ok(ImportOutcome::Relocated(_)) | Ok(ImportOutcome::AlreadyExists) => {
+                progress.skipped += 1
+            }
+            Err(reason) => {
+                progress.failed += 1;
+                failures.push(ImportFailure { path, reason });
+            }
+        }
+
+        batch += 1;
+        if batch >= options.batch_size.max(1) {
+            tx.commit()?;
+            tx = conn.transaction()?;
+            batch = 0;
+            on_progress(&progress);
+        }
+    }
+    tx.commit()?;
+    on_progress(&progress);
+
+    Ok(ImportReport {
+        progress,
+        failures,
+        cancelled,
+    })
+}
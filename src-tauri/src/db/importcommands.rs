@@ -0,0 +1,33 @@
+use super::error::LibraryError;
+use super::import::{ImportOptions, ImportProgress, ImportReport};
+use super::library::Library;
+use super::pool::DbPool;
+use crate::jobs::JobRegistry;
+use tauri::{ipc::Channel, State};
+
+/// Import every supported audio file under `path`, streaming `ImportProgress` on `on_progress`.
+/// `import_id` is chosen by the caller, it is the job id to cancel the import with `cancel_job`.
+/// Returns the counters and the files that failed, with the reason.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn import_folder(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    import_id: String,
+    path: String,
+    options: Option<ImportOptions>,
+    on_progress: Channel<ImportProgress>,
+) -> Result<ImportReport, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("import_folder", Some(import_id), move |job| {
+        Library::from_pool(&pool)?.import_folder(
+            &path,
+            &options.unwrap_or_default(),
+            job.cancel_flag(),
+            |progress| {
+                job.progress(progress.scanned, None);
+                let _ = on_progress.send(progress.clone());
+            },
+        )
+    })
+    .await?
+}
@@ -0,0 +1,1272 @@
+use super::backup::{self, CheckReport};
+use super::duplicates::{
+    duplicate_groups, fingerprint_tracks, merge_tracks, similar_recordings, DuplicateGroup,
+    FingerprintProgress, MergeReport, SimilarGroup,
+};
+use super::entities::{Album, Artist, LibraryFolder, LibraryRoot, Playlist, Track, TrackColumns};
+use super::error::LibraryError;
+use super::health::{self, vacuum, HealthReport, PrefixRewrite, RelocationReport, VacuumReport};
+use super::import::{
+    self, canonical_path, update_content_hash, update_track_details, update_track_identity,
+    ImportOptions, ImportOutcome, ImportProgress, ImportReport, BATCH_SIZE,
+};
+use super::libraryfolders::{self, FolderFilter, ReconcileSummary};
+use super::pool::{DbConnection, DbPool};
+use super::roots::{self, native_path, register_root, store_path};
+use super::trackcommands::TrackTagDiff;
+use super::utils::{parse_chapters, probe_track_duration, Chapter};
+use crate::artwork::store_album_artwork;
+use crate::decoder::{probe_audio_info, AudioInfo};
+use crate::metadata::{read_track_metadata, write_track_tags, TagChanges};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::collections::HashSet;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+enum Handle {
+    Owned(Connection),
+    Pooled(DbConnection),
+}
+
+impl Deref for Handle {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Handle::Owned(conn) => conn,
+            Handle::Pooled(conn) => conn,
+        }
+    }
+}
+
+impl DerefMut for Handle {
+    fn deref_mut(&mut self) -> &mut Connection {
+        match self {
+            Handle::Owned(conn) => conn,
+            Handle::Pooled(conn) => conn,
+        }
+    }
+}
+
+/// Copies of the files being retagged, to put them back if the batch fails.
+#[derive(Default)]
+struct FileBackups {
+    files: Vec<(String, PathBuf)>,
+}
+
+impl FileBackups {
+    fn backup(&mut self, path: &str) -> std::io::Result<()> {
+        let backup = std::env::temp_dir().join(format!("rwave-tags-{}", uuid::Uuid::new_v4()));
+        std::fs::copy(path, &backup)?;
+        self.files.push((path.to_string(), backup));
+        Ok(())
+    }
+
+    /// Puts every original file back
+    fn restore(&self) {
+        for (path, backup) in &self.files {
+            if let Err(e) = std::fs::copy(backup, path) {
+                log::error!(
+                    "Could not restore {} from {}: {}",
+                    path,
+                    backup.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl Drop for FileBackups {
+    fn drop(&mut self) {
+        for (_, backup) in &self.files {
+            let _ = std::fs::remove_file(backup);
+        }
+    }
+}
+
+/// The library operations, on a connection of its own. The commands and the HTTP server
+/// take one from the pool with `from_pool`, `open` opens a database of its own, e.g. to
+/// create and migrate it before the pool does, or in the tests.
+pub struct Library {
+    conn: Handle,
+}
+
+impl Library {
+    /// Opens the library at `path`, creating it if needed, and migrates it.
+    /// `:memory:` for one that lives as long as it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LibraryError> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        super::migrations::migrate(&mut conn)?;
+        Ok(Library {
+            conn: Handle::Owned(conn),
+        })
+    }
+
+    /// Uses a connection of the pool, whose database `prepare_database` already migrated.
+    pub fn from_pool(pool: &DbPool) -> Result<Self, LibraryError> {
+        Ok(Library {
+            conn: Handle::Pooled(pool.get()?),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    fn playlist_exists(&self, playlist_id: i32) -> Result<(), LibraryError> {
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM Playlists WHERE PlaylistID = ?)",
+            params![playlist_id],
+            |row| row.get(0),
+        )?;
+        match exists {
+            true => Ok(()),
+            false => Err(LibraryError::NotFound(format!("Playlist {}", playlist_id))),
+        }
+    }
+
+    fn track_exists(&self, track_id: i64) -> Result<(), LibraryError> {
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM Tracks WHERE TrackID = ?)",
+            params![track_id],
+            |row| row.get(0),
+        )?;
+        match exists {
+            true => Ok(()),
+            false => Err(LibraryError::NotFound(format!("Track {}", track_id))),
+        }
+    }
+
+    /// The path of a track on this machine
+    fn track_path(&self, track_id: i64) -> Result<String, LibraryError> {
+        self.conn
+            .query_row(
+                "SELECT FullPath FROM TrackFiles WHERE TrackID = ?",
+                params![track_id],
+                |row| row.get(0).map(native_path),
+            )
+            .optional()?
+            .ok_or_else(|| LibraryError::NotFound(format!("Track {}", track_id)))
+    }
+
+    /// The `(id, path)` of the tracks `sql` selects, as `TrackID, FullPath`
+    fn track_files(&self, sql: &str) -> Result<Vec<(i64, String)>, LibraryError> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, native_path(row.get(1)?))))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Adds the file at `path`, see `import::import_track`.
+    pub fn import_track(&mut self, path: &str) -> Result<ImportOutcome, LibraryError> {
+        if !Path::new(path).is_file() {
+            return Err(LibraryError::NotFound(format!("File {}", path)));
+        }
+        import::import_track(&mut self.conn, path)
+    }
+
+    /// Adds every supported file under `path`, see `import::import_folder`.
+    pub fn import_folder<F>(
+        &mut self,
+        path: &str,
+        options: &ImportOptions,
+        cancel: &AtomicBool,
+        on_progress: F,
+    ) -> Result<ImportReport, LibraryError>
+    where
+        F: FnMut(&ImportProgress),
+    {
+        Ok(import::import_folder(
+            &mut self.conn,
+            path,
+            options,
+            cancel,
+            on_progress,
+        )?)
+    }
+
+    pub fn track(&self, track_id: i32) -> Result<Track, LibraryError> {
+        self.conn
+            .query_row(
+                "SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) WHERE TrackID = ?",
+                params![track_id],
+                Track::from_row,
+            )
+            .optional()?
+            .ok_or_else(|| LibraryError::NotFound(format!("Track {}", track_id)))
+    }
+
+    /// Removes a track from the library, and from its playlists with it (`ON DELETE CASCADE`).
+    /// Its file is kept.
+    pub fn delete_track(&self, track_id: i32) -> Result<(), LibraryError> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM Tracks WHERE TrackID = ?", params![track_id])?;
+        if deleted == 0 {
+            return Err(LibraryError::NotFound(format!("Track {}", track_id)));
+        }
+        Ok(())
+    }
+
+    pub fn tracks(&self) -> Result<Vec<Track>, LibraryError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM Tracks JOIN TrackFiles USING (TrackID)")?;
+        let columns = TrackColumns::new(&stmt)?;
+        let rows = stmt.query_map([], |row| columns.read(row))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Renames a track and points it to the file at `path`, which no other track may use.
+    pub fn update_track(&self, track_id: i32, name: &str, path: &str) -> Result<(), LibraryError> {
+        let (root_id, stored) = store_path(&self.conn, path)?;
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE Tracks SET Name = ?1, RootID = ?2, Path = ?3 WHERE TrackID = ?4",
+                params![name, root_id, stored, track_id],
+            )
+            .map_err(|e| match e.sqlite_error_code() {
+                Some(ErrorCode::ConstraintViolation) => {
+                    LibraryError::AlreadyExists(format!("Track {}", path))
+                }
+                _ => LibraryError::from(e),
+            })?;
+        if updated == 0 {
+            return Err(LibraryError::NotFound(format!("Track {}", track_id)));
+        }
+        Ok(())
+    }
+
+    pub fn album(&self, album_id: i32) -> Result<Album, LibraryError> {
+        self.conn
+            .query_row(
+                "SELECT * FROM Albums WHERE AlbumID = ?",
+                params![album_id],
+                |row| {
+                    Ok(Album {
+                        album_id: row.get(0)?,
+                        name: row.get(1)?,
+                        artist_id: row.get(2)?,
+                        artwork_id: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| LibraryError::NotFound(format!("Album {}", album_id)))
+    }
+
+    pub fn artist(&self, artist_id: i32) -> Result<Artist, LibraryError> {
+        self.conn
+            .query_row(
+                "SELECT * FROM Artists WHERE ArtistID = ?",
+                params![artist_id],
+                |row| {
+                    Ok(Artist {
+                        artist_id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .ok_or_else(|| LibraryError::NotFound(format!("Artist {}", artist_id)))
+    }
+
+    /// The ID3 chapters of a track, see `utils::parse_chapters`.
+    pub fn chapters(&self, track_id: i32) -> Result<Vec<Chapter>, LibraryError> {
+        let path = self.track_path(track_id.into())?;
+        parse_chapters(&path).map_err(|e| LibraryError::tag(&path, e))
+    }
+
+    pub fn audio_info(&self, track_id: i32) -> Result<AudioInfo, LibraryError> {
+        let path = self.track_path(track_id.into())?;
+        probe_audio_info(&path).map_err(|e| LibraryError::Decode {
+            message: e.to_string(),
+            path,
+        })
+    }
+
+    /// Reads the duration of the tracks stored without one, `BATCH_SIZE` tracks per
+    /// transaction. Returns the number of tracks updated.
+    /// `cancel` is checked between files, `on_progress` gets `(done, total)`.
+    pub fn backfill_durations<F>(
+        &mut self,
+        cancel: &AtomicBool,
+        mut on_progress: F,
+    ) -> Result<usize, LibraryError>
+    where
+        F: FnMut(usize, usize),
+    {
+        let tracks = self.track_files(
+            "SELECT TrackID, FullPath FROM Tracks JOIN TrackFiles USING (TrackID)
+            WHERE Duration IS NULL OR Duration = 0",
+        )?;
+
+        let mut tx = self.conn.transaction()?;
+        let mut updated = 0;
+        let total = tracks.len();
+        for (done, (track_id, path)) in tracks.into_iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            match probe_track_duration(&path).filter(|duration| *duration > 0) {
+                Some(duration) => {
+                    tx.execute(
+                        "UPDATE Tracks SET Duration = ?1 WHERE TrackID = ?2",
+                        params![duration, track_id],
+                    )?;
+                    updated += 1;
+                }
+                None => log::warn!("Could not read the duration of {}", path),
+            }
+            on_progress(done + 1, total);
+            if (done + 1) % BATCH_SIZE == 0 {
+                tx.commit()?;
+                tx = self.conn.transaction()?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(updated)
+    }
+
+    /// Reads the tags and file properties of every track again, like `backfill_durations`.
+    /// Files that can't be read are skipped.
+    pub fn rescan_track_details<F>(
+        &mut self,
+        cancel: &AtomicBool,
+        mut on_progress: F,
+    ) -> Result<usize, LibraryError>
+    where
+        F: FnMut(usize, usize),
+    {
+        let tracks = self.track_files("SELECT TrackID, FullPath FROM TrackFiles")?;
+
+        let mut tx = self.conn.transaction()?;
+        let mut updated = 0;
+        let total = tracks.len();
+        for (done, (track_id, path)) in tracks.into_iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            match read_track_metadata(&path) {
+                Ok(metadata) => {
+                    update_track_details(&tx, track_id, &metadata)?;
+                    updated += 1;
+                }
+                Err(e) => log::warn!("Could not read the tags of {}: {}", path, e),
+            }
+            on_progress(done + 1, total);
+            if (done + 1) % BATCH_SIZE == 0 {
+                tx.commit()?;
+                tx = self.conn.transaction()?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(updated)
+    }
+
+    /// Writes `changes` to the files of the tracks and updates their rows, or with `dry_run`,
+    /// only returns the diff of every file. On the first failure, the files written so far
+    /// are restored and the transaction is rolled back.
+    pub fn update_track_tags(
+        &mut self,
+        track_ids: &[i64],
+        changes: &TagChanges,
+        dry_run: bool,
+    ) -> Result<Vec<TrackTagDiff>, LibraryError> {
+        let mut plan = Vec::new();
+        for &track_id in track_ids {
+            let path = self.track_path(track_id)?;
+            let current = read_track_metadata(&path).map_err(|e| LibraryError::tag(&path, e))?;
+            let updated = current.with_changes(changes);
+            plan.push((track_id, path, current, updated));
+        }
+
+        let diffs: Vec<TrackTagDiff> = plan
+            .iter()
+            .map(|(track_id, path, current, updated)| TrackTagDiff {
+                track_id: *track_id,
+                path: path.clone(),
+                changes: current.diff(updated),
+            })
+            .collect();
+        if dry_run {
+            return Ok(diffs);
+        }
+
+        let tx = self.conn.transaction()?;
+        let mut backups = FileBackups::default();
+        let written: Result<(), LibraryError> = plan
+            .iter()
+            .zip(&diffs)
+            .filter(|(_, diff)| !diff.changes.is_empty())
+            .try_for_each(|((track_id, path, _, updated), _)| {
+                backups.backup(path).map_err(|source| LibraryError::Io {
+                    path: path.clone(),
+                    source,
+                })?;
+                write_track_tags(path, updated).map_err(|e| LibraryError::tag(path, e))?;
+                update_track_identity(&tx, *track_id, updated)?;
+
+                // The file size, mtime and content hash changed with the write
+                let on_disk = read_track_metadata(path).unwrap_or_else(|_| updated.clone());
+                update_track_details(&tx, *track_id, &on_disk)?;
+                update_content_hash(&tx, *track_id, path)?;
+                Ok(())
+            });
+
+        if let Err(e) = written.and_then(|_| tx.commit().map_err(LibraryError::from)) {
+            backups.restore();
+            return Err(e);
+        }
+
+        Ok(diffs)
+    }
+
+    /// Looks for the artwork of every album that has none, in the tags of its tracks
+    /// or next to them. Returns the number of albums that got an artwork.
+    pub fn rescan_artwork<F>(
+        &self,
+        cancel: &AtomicBool,
+        mut on_progress: F,
+    ) -> Result<usize, LibraryError>
+    where
+        F: FnMut(usize, usize),
+    {
+        let tracks = self.track_files(
+            "SELECT Albums.AlbumID, TrackFiles.FullPath FROM Albums
+            JOIN Tracks ON Tracks.AlbumID = Albums.AlbumID
+            JOIN TrackFiles ON TrackFiles.TrackID = Tracks.TrackID
+            WHERE Albums.ArtworkID IS NULL",
+        )?;
+
+        let mut found = HashSet::new();
+        let total = tracks.len();
+        for (done, (album_id, path)) in tracks.into_iter().enumerate() {
+            on_progress(done, total);
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            if found.contains(&album_id) {
+                continue;
+            }
+            match store_album_artwork(&self.conn, album_id, &path) {
+                Ok(Some(_)) => {
+                    found.insert(album_id);
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Could not read the artwork of {}: {}", path, e),
+            }
+        }
+
+        Ok(found.len())
+    }
+
+    pub fn playlists(&self) -> Result<Vec<Playlist>, LibraryError> {
+        let mut stmt = self.conn.prepare("SELECT * FROM Playlists")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Playlist {
+                playlist_id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Adds an empty playlist, returning its id. Playlist names are unique.
+    pub fn create_playlist(&self, name: &str) -> Result<i32, LibraryError> {
+        let exists: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM Playlists WHERE Name = ?)",
+            params![name],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Err(LibraryError::AlreadyExists(format!("Playlist {}", name)));
+        }
+
+        self.conn
+            .execute("INSERT INTO Playlists (Name) VALUES (?)", params![name])?;
+        Ok(self.conn.last_insert_rowid() as i32)
+    }
+
+    pub fn rename_playlist(&self, playlist_id: i32, name: &str) -> Result<(), LibraryError> {
+        self.playlist_exists(playlist_id)?;
+        self.conn.execute(
+            "UPDATE Playlists SET Name = ? WHERE PlaylistID = ?",
+            params![name, playlist_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_playlist(&self, playlist_id: i32) -> Result<(), LibraryError> {
+        self.playlist_exists(playlist_id)?;
+        // Its tracks leave it with it, `ON DELETE CASCADE`
+        self.conn.execute(
+            "DELETE FROM Playlists WHERE PlaylistID = ?",
+            params![playlist_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_to_playlist(&self, playlist_id: i32, track_id: i32) -> Result<(), LibraryError> {
+        self.playlist_exists(playlist_id)?;
+        self.track_exists(track_id.into())?;
+
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID) VALUES (?, ?)",
+            params![track_id, playlist_id],
+        )?;
+        if inserted == 0 {
+            return Err(LibraryError::AlreadyExists(format!(
+                "Track {} in playlist {}",
+                track_id, playlist_id
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn remove_from_playlist(
+        &self,
+        playlist_id: i32,
+        track_id: i32,
+    ) -> Result<(), LibraryError> {
+        self.playlist_exists(playlist_id)?;
+        self.track_exists(track_id.into())?;
+
+        self.conn.execute(
+            "DELETE FROM TrackPlaylist WHERE PlaylistID = ? AND TrackID = ?",
+            params![playlist_id, track_id],
+        )?;
+        Ok(())
+    }
+
+    /// The tracks of a playlist, with their resolved paths.
+    pub fn playlist_tracks(&self, playlist_id: i32) -> Result<Vec<Track>, LibraryError> {
+        self.playlist_exists(playlist_id)?;
+
+        let mut stmt = self.conn.prepare_cached(
+            "
+            SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) JOIN TrackPlaylist
+            ON Tracks.TrackID = TrackPlaylist.TrackID
+            WHERE TrackPlaylist.PlaylistID = ? ",
+        )?;
+        let columns = TrackColumns::new(&stmt)?;
+        let rows = stmt.query_map(params![playlist_id], |row| columns.read(row))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// The groups of tracks with the same audio, see `duplicates::duplicate_groups`.
+    /// `cancel` is checked between files, `on_progress` gets `(done, total)`.
+    pub fn duplicates<F>(
+        &mut self,
+        cancel: &AtomicBool,
+        on_progress: F,
+    ) -> Result<Vec<DuplicateGroup>, LibraryError>
+    where
+        F: FnMut(usize, usize),
+    {
+        Ok(duplicate_groups(&mut self.conn, cancel, on_progress)?)
+    }
+
+    /// Fingerprints the tracks that have none yet, see `duplicates::fingerprint_tracks`.
+    pub fn fingerprint<F>(&self, cancel: &AtomicBool, on_progress: F) -> Result<usize, LibraryError>
+    where
+        F: FnMut(&FingerprintProgress),
+    {
+        Ok(fingerprint_tracks(&self.conn, cancel, on_progress)?)
+    }
+
+    /// The groups of tracks that sound alike, see `duplicates::similar_recordings`.
+    pub fn similar_recordings(&self, threshold: f32) -> Result<Vec<SimilarGroup>, LibraryError> {
+        Ok(similar_recordings(&self.conn, threshold)?)
+    }
+
+    /// Folds `remove_ids` into `keep_id`, see `duplicates::merge_tracks`.
+    pub fn merge_duplicates(
+        &mut self,
+        keep_id: i64,
+        remove_ids: &[i64],
+        delete_files: bool,
+    ) -> Result<MergeReport, LibraryError> {
+        self.track_exists(keep_id)?;
+        merge_tracks(&mut self.conn, keep_id, remove_ids, delete_files)
+    }
+
+    /// Deletes the albums and artists left without tracks, and the artworks left without albums.
+    pub fn vacuum(&mut self) -> Result<VacuumReport, LibraryError> {
+        Ok(vacuum(&mut self.conn)?)
+    }
+
+    /// Flags the tracks whose file is gone, see `health::check_health`.
+    pub fn check_health(&mut self) -> Result<HealthReport, LibraryError> {
+        Ok(health::check_health(&mut self.conn)?)
+    }
+
+    /// Re-links the missing tracks to their files under `new_root`,
+    /// see `health::relocate_missing`.
+    pub fn relocate_missing(
+        &mut self,
+        new_root: &str,
+        dry_run: bool,
+    ) -> Result<RelocationReport, LibraryError> {
+        Ok(health::relocate_missing(&mut self.conn, new_root, dry_run)?)
+    }
+
+    /// Moves the paths starting with `old_prefix` to `new_prefix`, see `health::rewrite_prefix`.
+    pub fn rewrite_prefix(
+        &mut self,
+        old_prefix: &str,
+        new_prefix: &str,
+        dry_run: bool,
+    ) -> Result<PrefixRewrite, LibraryError> {
+        Ok(health::rewrite_prefix(
+            &mut self.conn,
+            old_prefix,
+            new_prefix,
+            dry_run,
+        )?)
+    }
+
+    /// The library roots, innermost first.
+    pub fn roots(&self) -> Result<Vec<LibraryRoot>, LibraryError> {
+        Ok(roots::load_roots(&self.conn)?)
+    }
+
+    /// Registers the folder `path` as a library root, see `roots::register_root`.
+    /// Returns the id of the root containing it.
+    pub fn add_root(&mut self, path: &str) -> Result<i64, LibraryError> {
+        if !Path::new(path).is_dir() {
+            return Err(LibraryError::NotFound(format!("Folder {}", path)));
+        }
+        let tx = self.conn.transaction()?;
+        let root_id = register_root(&tx, &canonical_path(Path::new(path)))?;
+        tx.commit()?;
+        Ok(root_id)
+    }
+
+    /// Points a root to the folder `path`, which relinks all its tracks.
+    pub fn move_root(&mut self, root_id: i64, path: &str) -> Result<(), LibraryError> {
+        if !Path::new(path).is_dir() {
+            return Err(LibraryError::NotFound(format!("Folder {}", path)));
+        }
+        roots::move_root(&mut self.conn, root_id, &canonical_path(Path::new(path))).map_err(|e| {
+            match e {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    LibraryError::NotFound(format!("Library root {}", root_id))
+                }
+                e => LibraryError::from(e),
+            }
+        })
+    }
+
+    pub fn folders(&self) -> Result<Vec<LibraryFolder>, LibraryError> {
+        Ok(libraryfolders::load_folders(&self.conn)?)
+    }
+
+    /// Adds a library folder, under its canonical path, and registers it as a root.
+    /// Its files are imported by `reconcile_folder`.
+    pub fn add_folder(&mut self, folder: LibraryFolder) -> Result<LibraryFolder, LibraryError> {
+        FolderFilter::new(&folder).map_err(|e| LibraryError::Invalid(e.to_string()))?;
+        if !Path::new(&folder.path).is_dir() {
+            return Err(LibraryError::NotFound(format!("Folder {}", folder.path)));
+        }
+        // Tracks are stored under their canonical path, so must be the folder
+        let folder = LibraryFolder {
+            path: canonical_path(Path::new(&folder.path)),
+            ..folder
+        };
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO LibraryFolders (Path, IncludePatterns, ExcludePatterns, MinDuration)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                folder.path,
+                serde_json::to_string(&folder.include_patterns).unwrap(),
+                serde_json::to_string(&folder.exclude_patterns).unwrap(),
+                folder.min_duration as i64,
+            ],
+        )
+        .map_err(|e| match e.sqlite_error_code() {
+            Some(ErrorCode::ConstraintViolation) => {
+                LibraryError::AlreadyExists(format!("Library folder {}", folder.path))
+            }
+            _ => LibraryError::from(e),
+        })?;
+        let folder = LibraryFolder {
+            folder_id: Some(tx.last_insert_rowid() as i32),
+            ..folder
+        };
+        // Its tracks are stored relative to it, or to the root it is in
+        register_root(&tx, &folder.path)?;
+        tx.commit()?;
+        Ok(folder)
+    }
+
+    /// Removes a library folder, returning its path. Its tracks stay in the library.
+    pub fn remove_folder(&self, folder_id: i32) -> Result<String, LibraryError> {
+        let path: String = self
+            .conn
+            .query_row(
+                "SELECT Path FROM LibraryFolders WHERE FolderID = ?",
+                params![folder_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| LibraryError::NotFound(format!("Library folder {}", folder_id)))?;
+        self.conn.execute(
+            "DELETE FROM LibraryFolders WHERE FolderID = ?",
+            params![folder_id],
+        )?;
+        Ok(path)
+    }
+
+    /// Syncs the library with the files of a folder, see `libraryfolders::reconcile_folder`.
+    pub fn reconcile_folder(
+        &mut self,
+        folder: &LibraryFolder,
+    ) -> Result<ReconcileSummary, LibraryError> {
+        Ok(libraryfolders::reconcile_folder(&mut self.conn, folder)?)
+    }
+
+    /// Reconciles every library folder, see `libraryfolders::reconcile_all`.
+    pub fn rescan_folders(&mut self) -> Result<ReconcileSummary, LibraryError> {
+        Ok(libraryfolders::reconcile_all(&mut self.conn)?)
+    }
+
+    /// Copies the library to the file `dest`, which can't be the library itself.
+    pub fn backup(&self, dest: &Path) -> Result<(), LibraryError> {
+        let path = self.conn.path().map(Path::new);
+        if dest.exists()
+            && path.and_then(|p| dunce::canonicalize(p).ok()) == dunce::canonicalize(dest).ok()
+        {
+            return Err(LibraryError::Invalid(
+                "The library can't be backed up onto itself".to_string(),
+            ));
+        }
+        Ok(backup::backup_to(&self.conn, dest)?)
+    }
+
+    /// Replaces the library with the backup at `src`, see `backup::restore_from`.
+    pub fn restore(&mut self, src: &Path) -> Result<(), LibraryError> {
+        if !src.is_file() {
+            return Err(LibraryError::NotFound(format!("File {}", src.display())));
+        }
+        let path = PathBuf::from(self.conn.path().unwrap_or_default());
+        backup::restore_from(&mut self.conn, &path, src).map_err(|e| {
+            match e.downcast::<rusqlite::Error>() {
+                Ok(e) => LibraryError::Db(*e),
+                Err(e) => LibraryError::Invalid(e.to_string()),
+            }
+        })
+    }
+
+    /// Checks the database file and its references, see `backup::check`.
+    pub fn check(&self) -> Result<CheckReport, LibraryError> {
+        Ok(backup::check(&self.conn)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::{write_wav, write_wav_lasting};
+    use std::time::Duration;
+
+    const ALL_TRACKS: i32 = 1;
+    /// A temporary folder with `a.wav` and `b.wav`, the same tone tagged differently,
+    /// and `c.wav`, another tone.
+    fn fixtures(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rwave-library-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_wav(&dir.join("a.wav"), 440.0, "Song", "Artist", "Album");
+        write_wav(&dir.join("b.wav"), 440.0, "Song (Copy)", "Artist", "Copies");
+        write_wav(
+            &dir.join("c.wav"),
+            660.0,
+            "Other",
+            "Other Artist",
+            "Other Album",
+        );
+        dir
+    }
+
+    fn file(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    fn created(outcome: ImportOutcome) -> i32 {
+        match outcome {
+            ImportOutcome::Created(track_id) => track_id as i32,
+            _ => panic!("The track wasn't created"),
+        }
+    }
+
+    fn track_ids(tracks: Vec<Track>) -> Vec<i32> {
+        tracks.iter().filter_map(|track| track.track_id).collect()
+    }
+
+    #[test]
+    fn imports_tagged_files() {
+        let dir = fixtures("import");
+        let mut library = Library::open(":memory:").unwrap();
+
+        let report = library
+            .import_folder(
+                &dir.to_string_lossy(),
+                &ImportOptions::default(),
+                &AtomicBool::new(false),
+                |_| {},
+            )
+            .unwrap();
+        assert_eq!(report.progress.added, 3);
+        assert!(report.failures.is_empty());
+
+        let tracks = library.playlist_tracks(ALL_TRACKS).unwrap();
+        assert_eq!(tracks.len(), 3);
+        let song = tracks.iter().find(|track| track.name == "Song").unwrap();
+        let (artist, album): (String, String) = library
+            .connection()
+            .query_row(
+                "SELECT Artists.Name, Albums.Name FROM Tracks
+                JOIN Artists USING (ArtistID) JOIN Albums USING (AlbumID)
+                WHERE TrackID = ?",
+                params![song.track_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((artist.as_str(), album.as_str()), ("Artist", "Album"));
+
+        assert!(matches!(
+            library.import_track(&file(&dir, "a.wav")).unwrap(),
+            ImportOutcome::AlreadyExists
+        ));
+        let missing = library.import_track(&file(&dir, "missing.wav"));
+        assert_eq!(missing.unwrap_err().code(), "not_found");
+
+        // A file that can't be read is reported, not added without tags
+        std::fs::write(dir.join("broken.wav"), b"not a wav file").unwrap();
+        let broken = library.import_track(&file(&dir, "broken.wav"));
+        assert_eq!(broken.unwrap_err().code(), "tag");
+        assert_eq!(library.playlist_tracks(ALL_TRACKS).unwrap().len(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stores_loop_points() {
+        use id3::{frame::ExtendedText, TagLike};
+
+        let dir = fixtures("loop-points");
+        let mut tag = id3::Tag::new();
+        for (description, value) in [("LOOPSTART", "100"), ("LOOPLENGTH", "400")] {
+            tag.add_frame(ExtendedText {
+                description: description.to_string(),
+                value: value.to_string(),
+            });
+        }
+        tag.write_to_path(dir.join("a.wav"), id3::Version::Id3v24)
+            .unwrap();
+
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+        let track = library.track(a).unwrap();
+        assert_eq!(
+            (track.loop_start, track.loop_length),
+            (Some(100), Some(400))
+        );
+        let track = library.track(c).unwrap();
+        assert_eq!((track.loop_start, track.loop_length), (None, None));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manages_playlists() {
+        let dir = fixtures("playlists");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+
+        let playlist = library.create_playlist("Favorites").unwrap();
+        let again = library.create_playlist("Favorites");
+        assert_eq!(again.unwrap_err().code(), "already_exists");
+
+        library.add_to_playlist(playlist, a).unwrap();
+        library.add_to_playlist(playlist, c).unwrap();
+        let again = library.add_to_playlist(playlist, a);
+        assert_eq!(again.unwrap_err().code(), "already_exists");
+        let unknown = library.add_to_playlist(playlist, 999);
+        assert_eq!(unknown.unwrap_err().code(), "not_found");
+
+        library.rename_playlist(playlist, "Best").unwrap();
+        assert!(library
+            .playlists()
+            .unwrap()
+            .iter()
+            .any(|p| p.playlist_id == Some(playlist) && p.name == "Best"));
+
+        library.remove_from_playlist(playlist, c).unwrap();
+        assert_eq!(track_ids(library.playlist_tracks(playlist).unwrap()), [a]);
+
+        library.delete_playlist(playlist).unwrap();
+        let deleted = library.playlist_tracks(playlist);
+        assert_eq!(deleted.unwrap_err().code(), "not_found");
+        assert!(library.track(a).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merges_duplicates() {
+        let dir = fixtures("duplicates");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let b = created(library.import_track(&file(&dir, "b.wav")).unwrap());
+        created(library.import_track(&file(&dir, "c.wav")).unwrap());
+
+        // Tracks imported before their audio was hashed on import have no hash
+        library
+            .connection()
+            .execute("UPDATE Tracks SET AudioHash = NULL", [])
+            .unwrap();
+
+        // Cancelled before the first file, nothing is hashed
+        let cancelled = AtomicBool::new(true);
+        assert!(library
+            .duplicates(&cancelled, |_, _| {})
+            .unwrap()
+            .is_empty());
+
+        let mut progress = Vec::new();
+        let groups = library
+            .duplicates(&AtomicBool::new(false), |done, total| {
+                progress.push((done, total))
+            })
+            .unwrap();
+        assert_eq!(progress, [(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(track_ids(groups.into_iter().next().unwrap().tracks), [a, b]);
+
+        let playlist = library.create_playlist("Mix").unwrap();
+        library.add_to_playlist(playlist, b).unwrap();
+        let report = library
+            .merge_duplicates(a.into(), &[b.into()], true)
+            .unwrap();
+        assert_eq!(report.merged, 1);
+        assert_eq!(report.deleted_files.len(), 1);
+        assert!(!dir.join("b.wav").exists());
+
+        assert_eq!(track_ids(library.playlist_tracks(playlist).unwrap()), [a]);
+        assert_eq!(library.track(b).unwrap_err().code(), "not_found");
+        let groups = library.duplicates(&AtomicBool::new(false), |_, _| {});
+        assert!(groups.unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merge_keeps_play_counts_and_ratings() {
+        let dir = fixtures("merge-stats");
+        write_wav(&dir.join("d.wav"), 440.0, "Song (Live)", "Artist", "Live");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let b = created(library.import_track(&file(&dir, "b.wav")).unwrap());
+        let d = created(library.import_track(&file(&dir, "d.wav")).unwrap());
+        for (track_id, play_count, rating) in [(a, 2, None), (b, 3, Some(4)), (d, 4, Some(1))] {
+            library
+                .connection()
+                .execute(
+                    "UPDATE Tracks SET PlayCount = ?1, Rating = ?2 WHERE TrackID = ?3",
+                    params![play_count, rating, track_id],
+                )
+                .unwrap();
+        }
+
+        // The kept track has no rating, it takes the one of the merged track
+        library
+            .merge_duplicates(a.into(), &[b.into()], false)
+            .unwrap();
+        let kept = library.track(a).unwrap();
+        assert_eq!((kept.play_count, kept.rating), (5, Some(4)));
+
+        // It has one now, and keeps it
+        library
+            .merge_duplicates(a.into(), &[d.into()], false)
+            .unwrap();
+        let kept = library.track(a).unwrap();
+        assert_eq!((kept.play_count, kept.rating), (9, Some(4)));
+        assert!(dir.join("b.wav").exists() && dir.join("d.wav").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merge_refuses_other_audio() {
+        let dir = fixtures("merge-refused");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let b = created(library.import_track(&file(&dir, "b.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+        let playlist = library.create_playlist("Mix").unwrap();
+        library.add_to_playlist(playlist, b).unwrap();
+        library.add_to_playlist(playlist, c).unwrap();
+
+        // `c` is another tone: nothing is merged, not even the duplicate `b`
+        let refused = library.merge_duplicates(a.into(), &[b.into(), c.into()], true);
+        assert_eq!(refused.unwrap_err().code(), "invalid");
+        assert_eq!(
+            track_ids(library.playlist_tracks(playlist).unwrap()),
+            [b, c]
+        );
+        assert!(library.track(b).is_ok() && library.track(c).is_ok());
+        assert!(dir.join("b.wav").exists() && dir.join("c.wav").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn deletes_tracks() {
+        let dir = fixtures("deletes");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+        let playlist = library.create_playlist("Mix").unwrap();
+        library.add_to_playlist(playlist, c).unwrap();
+
+        library.delete_track(c).unwrap();
+        assert_eq!(library.track(c).unwrap_err().code(), "not_found");
+        assert_eq!(library.delete_track(c).unwrap_err().code(), "not_found");
+        assert!(library.playlist_tracks(playlist).unwrap().is_empty());
+        assert_eq!(track_ids(library.playlist_tracks(ALL_TRACKS).unwrap()), [a]);
+        assert!(dir.join("c.wav").exists());
+
+        let report = library.vacuum().unwrap();
+        let albums: Vec<String> = report.albums.into_iter().map(|album| album.name).collect();
+        let artists: Vec<String> = report.artists.into_iter().map(|a| a.name).collect();
+        assert_eq!(albums, ["Other Album"]);
+        assert_eq!(artists, ["Other Artist"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn edits_tags() {
+        let dir = fixtures("tags");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let track = library.track(a).unwrap();
+        assert_eq!(
+            library.album(track.album_id.unwrap()).unwrap().name,
+            "Album"
+        );
+        assert_eq!(
+            library.artist(track.artist_id.unwrap()).unwrap().name,
+            "Artist"
+        );
+        assert_eq!(library.album(100).unwrap_err().code(), "not_found");
+        assert_eq!(library.chapters(100).unwrap_err().code(), "not_found");
+
+        let changes = TagChanges {
+            title: Some("Renamed".to_string()),
+            album: Some("Other Album".to_string()),
+            ..Default::default()
+        };
+        let diffs = library
+            .update_track_tags(&[a.into()], &changes, true)
+            .unwrap();
+        let fields: Vec<&str> = diffs[0].changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields.len(), 2);
+        // A dry run writes nothing
+        assert_eq!(library.track(a).unwrap().name, "Song");
+        let on_disk = read_track_metadata(&file(&dir, "a.wav")).unwrap();
+        assert_eq!(on_disk.title.as_deref(), Some("Song"));
+
+        library
+            .update_track_tags(&[a.into()], &changes, false)
+            .unwrap();
+        let track = library.track(a).unwrap();
+        assert_eq!(track.name, "Renamed");
+        assert_eq!(
+            library.album(track.album_id.unwrap()).unwrap().name,
+            "Other Album"
+        );
+        let on_disk = read_track_metadata(&file(&dir, "a.wav")).unwrap();
+        assert_eq!(on_disk.title.as_deref(), Some("Renamed"));
+
+        // A missing track fails the whole batch
+        let error = library.update_track_tags(&[a.into(), 100], &changes, false);
+        assert_eq!(error.unwrap_err().code(), "not_found");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backfills_durations() {
+        let dir = fixtures("durations");
+        let long = dir.join("long.wav");
+        write_wav_lasting(
+            &long,
+            Duration::from_secs(1),
+            330.0,
+            "Long",
+            "Artist",
+            "Album",
+        );
+        let mut library = Library::open(":memory:").unwrap();
+        let long = created(library.import_track(&long.to_string_lossy()).unwrap());
+        created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        library
+            .connection()
+            .execute("UPDATE Tracks SET Duration = NULL, Genre = 'Stale'", ())
+            .unwrap();
+
+        let mut progress = Vec::new();
+        let cancel = AtomicBool::new(false);
+        let updated = library
+            .backfill_durations(&cancel, |done, total| progress.push((done, total)))
+            .unwrap();
+        // a.wav lasts less than a second, which is no duration
+        assert_eq!(updated, 1);
+        assert_eq!(progress, [(1, 2), (2, 2)]);
+        assert_eq!(library.track(long).unwrap().duration, Some(1));
+
+        assert_eq!(library.rescan_track_details(&cancel, |_, _| {}).unwrap(), 2);
+        assert_eq!(library.track(long).unwrap().genre, None);
+
+        // Cancelled before the first file
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(library.backfill_durations(&cancel, |_, _| {}).unwrap(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_health_and_relocates() {
+        let dir = fixtures("health");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+        assert!(library.check_health().unwrap().missing.is_empty());
+
+        let moved = dir.with_extension("moved");
+        std::fs::rename(&dir, &moved).unwrap();
+        let health = library.check_health().unwrap();
+        assert_eq!(track_ids(health.missing), [a, c]);
+
+        let report = library
+            .relocate_missing(&moved.to_string_lossy(), false)
+            .unwrap();
+        assert_eq!(report.relinked.len(), 2);
+        assert_eq!(library.track(a).unwrap().path, file(&moved, "a.wav"));
+        assert!(library.check_health().unwrap().missing.is_empty());
+
+        // The tracks are outside any root, so they follow the prefix
+        std::fs::rename(&moved, &dir).unwrap();
+        let old_prefix = moved.to_string_lossy();
+        let new_prefix = dir.to_string_lossy();
+        let rewrite = library
+            .rewrite_prefix(&old_prefix, &new_prefix, false)
+            .unwrap();
+        assert_eq!((rewrite.tracks, rewrite.still_missing), (2, 0));
+        assert_eq!(library.track(c).unwrap().path, file(&dir, "c.wav"));
+        let health = library.check_health().unwrap();
+        assert!(health.missing.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manages_roots_and_folders() {
+        let dir = fixtures("roots");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+
+        let root = library.add_root(&dir.to_string_lossy()).unwrap();
+        let stored: (Option<i64>, String) = library
+            .connection()
+            .query_row(
+                "SELECT RootID, Path FROM Tracks WHERE TrackID = ?",
+                [a],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(stored, (Some(root), "a.wav".to_string()));
+        let roots = library.roots().unwrap();
+        assert_eq!(roots.len(), 1);
+        let missing = library.add_root(&file(&dir, "missing"));
+        assert_eq!(missing.unwrap_err().code(), "not_found");
+
+        // Moving the root relinks its tracks
+        let moved = dir.with_extension("moved");
+        std::fs::rename(&dir, &moved).unwrap();
+        library.move_root(root, &moved.to_string_lossy()).unwrap();
+        assert_eq!(library.track(a).unwrap().path, file(&moved, "a.wav"));
+        let unknown = library.move_root(root + 1, &moved.to_string_lossy());
+        assert_eq!(unknown.unwrap_err().code(), "not_found");
+
+        let folder = LibraryFolder {
+            folder_id: None,
+            path: moved.to_string_lossy().to_string(),
+            include_patterns: Vec::new(),
+            exclude_patterns: vec!["[".to_string()],
+            min_duration: 0,
+        };
+        let invalid = library.add_folder(folder.clone());
+        assert_eq!(invalid.unwrap_err().code(), "invalid");
+        let folder = LibraryFolder {
+            exclude_patterns: Vec::new(),
+            ..folder
+        };
+        let added = library.add_folder(folder.clone()).unwrap();
+        assert_eq!(
+            library.add_folder(folder).unwrap_err().code(),
+            "already_exists"
+        );
+        // The folder is the root, which is kept
+        assert_eq!(library.roots().unwrap().len(), 1);
+        let summary = library.reconcile_folder(&added).unwrap();
+        assert_eq!(summary.added, 2);
+
+        let folder_id = added.folder_id.unwrap();
+        assert_eq!(library.remove_folder(folder_id).unwrap(), added.path);
+        assert!(library.folders().unwrap().is_empty());
+        let removed = library.remove_folder(folder_id);
+        assert_eq!(removed.unwrap_err().code(), "not_found");
+
+        std::fs::remove_dir_all(moved).unwrap();
+    }
+
+    #[test]
+    fn updates_tracks() {
+        let dir = fixtures("update");
+        let mut library = Library::open(":memory:").unwrap();
+        let a = created(library.import_track(&file(&dir, "a.wav")).unwrap());
+        let c = created(library.import_track(&file(&dir, "c.wav")).unwrap());
+
+        library
+            .update_track(a, "Renamed", &file(&dir, "b.wav"))
+            .unwrap();
+        let track = library.track(a).unwrap();
+        assert_eq!(
+            (track.name.as_str(), track.path),
+            ("Renamed", file(&dir, "b.wav"))
+        );
+        assert_eq!(library.tracks().unwrap().len(), 2);
+
+        // Another track has the path
+        let taken = library.update_track(c, "Other", &file(&dir, "b.wav"));
+        assert_eq!(taken.unwrap_err().code(), "already_exists");
+        let unknown = library.update_track(100, "Other", &file(&dir, "d.wav"));
+        assert_eq!(unknown.unwrap_err().code(), "not_found");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
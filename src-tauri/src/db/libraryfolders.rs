@@ -0,0 +1,491 @@
+use super::entities::LibraryFolder;
+use super::error::LibraryError;
+use super::import::{
+    canonical_path, insert_track, is_supported_file, refresh_track, update_content_hash,
+    ImportOutcome, BATCH_SIZE,
+};
+use super::library::Library;
+use super::pool::DbPool;
+use super::roots::{native_path, store_path, stored_prefix};
+use crate::jobs::JobRegistry;
+use crate::metadata::read_track_metadata;
+use crate::watcher::LibraryWatcher;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, UNIX_EPOCH};
+use tauri::State;
+use walkdir::WalkDir;
+
+/// Decides which files of a library folder belong to the library.
+pub struct FolderFilter {
+    root: PathBuf,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    min_duration: u64,
+}
+
+fn build_globs(patterns: &[String]) -> std::result::Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+impl FolderFilter {
+    pub fn new(folder: &LibraryFolder) -> std::result::Result<Self, globset::Error> {
+        Ok(FolderFilter {
+            root: PathBuf::from(&folder.path),
+            include: match folder.include_patterns.is_empty() {
+                true => None,
+                false => Some(build_globs(&folder.include_patterns)?),
+            },
+            exclude: build_globs(&folder.exclude_patterns)?,
+            min_duration: folder.min_duration,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether `path` is an audio file matched by the patterns, relative to the folder.
+    pub fn accepts_path(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+
+        is_supported_file(path)
+            && !self.exclude.is_match(relative)
+            && self
+                .include
+                .as_ref()
+                .map_or(true, |set| set.is_match(relative))
+    }
+}
+
+/// What `sync_file` did with a file.
+#[derive(PartialEq)]
+pub enum SyncOutcome {
+    Added,
+    Updated,
+    /// A known track found under a new path, recognized by its content hash
+    Renamed,
+    Unchanged,
+    /// Filtered out, too short or already in the library under another path
+    Skipped,
+}
+
+/// Size and modification time of a file, as stored in `Tracks.FileSize`/`Tracks.MTime`.
+pub fn file_stamp(path: &Path) -> Option<(i64, i64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs() as i64;
+    Some((metadata.len() as i64, mtime))
+}
+
+/// Imports a file new to the library, or refreshes the track if the file changed
+/// (size or mtime) or was missing. Unchanged files are only `stat`ed, their tags aren't read.
+pub fn sync_file(conn: &Connection, filter: &FolderFilter, path: &Path) -> Result<SyncOutcome> {
+    if !filter.accepts_path(path) {
+        return Ok(SyncOutcome::Skipped);
+    }
+    let path_str = canonical_path(path);
+    let (root_id, stored) = store_path(conn, &path_str)?;
+
+    type Known = (i64, Option<i64>, Option<i64>, bool, Option<String>);
+    let known: Option<Known> = conn
+        .prepare_cached(
+            "SELECT TrackID, FileSize, MTime, Missing, ContentHash FROM Tracks
+            WHERE Path = ?1 AND RootID IS ?2",
+        )?
+        .query_row(params![stored, root_id], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })
+        .optional()?;
+
+    match known {
+        Some((track_id, size, mtime, missing, hash)) => {
+            let unchanged = file_stamp(path)
+                .map(|(file_size, file_mtime)| size == Some(file_size) && mtime == Some(file_mtime))
+                .unwrap_or(false);
+            if unchanged && !missing {
+                // Tracks imported before content hashes were stored
+                if hash.is_none() {
+                    update_content_hash(conn, track_id, &path_str)?;
+                }
+                return Ok(SyncOutcome::Unchanged);
+            }
+
+            if let Ok(metadata) = read_track_metadata(&path_str) {
+                refresh_track(conn, track_id, &path_str, &metadata)?;
+            }
+            conn.execute(
+                "UPDATE Tracks SET Missing = 0 WHERE TrackID = ?",
+                params![track_id],
+            )?;
+            Ok(SyncOutcome::Updated)
+        }
+        None => {
+            let metadata = match read_track_metadata(&path_str) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    log::warn!("Could not read the tags of {}: {}", path_str, e);
+                    return Ok(SyncOutcome::Skipped);
+                }
+            };
+            if metadata.duration.unwrap_or(0) < filter.min_duration {
+                return Ok(SyncOutcome::Skipped);
+            }
+
+            match insert_track(conn, &path_str, &metadata)? {
+                ImportOutcome::Created(_) => Ok(SyncOutcome::Added),
+                ImportOutcome::Relocated(_) => Ok(SyncOutcome::Renamed),
+                ImportOutcome::AlreadyExists => Ok(SyncOutcome::Skipped),
+            }
+        }
+    }
+}
+
+/// Flags the tracks of `path`, a file or a whole directory, as missing.
+/// Returns the number of tracks flagged.
+pub fn mark_missing(conn: &Connection, path: &Path) -> Result<usize> {
+    let (root_id, stored, prefix) = stored_prefix(conn, &path.to_string_lossy())?;
+    conn.execute(
+        "UPDATE Tracks SET Missing = 1
+        WHERE Missing = 0 AND RootID IS ?1 AND (Path = ?2 OR substr(Path, 1, length(?3)) = ?3)",
+        params![root_id, stored, prefix],
+    )
+}
+
+/// Counts of a reconciliation pass over library folders.
+#[derive(Default, Serialize)]
+pub struct ReconcileSummary {
+    /// Audio files found in the folders
+    pub scanned: usize,
+    pub unchanged: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub renamed: usize,
+    pub missing: usize,
+    pub elapsed_ms: u64,
+}
+
+impl ReconcileSummary {
+    fn add(&mut self, other: &ReconcileSummary) {
+        self.scanned += other.scanned;
+        self.unchanged += other.unchanged;
+        self.added += other.added;
+        self.updated += other.updated;
+        self.renamed += other.renamed;
+        self.missing += other.missing;
+        self.elapsed_ms += other.elapsed_ms;
+    }
+}
+
+/// Brings the tracks of a folder up to date with the files on disk: imports new files,
+/// refreshes changed ones and flags the ones that are gone as missing.
+/// The changes are committed `BATCH_SIZE` files at a time.
+pub fn reconcile_folder(conn: &mut Connection, folder: &LibraryFolder) -> Result<ReconcileSummary> {
+    let filter = match FolderFilter::new(folder) {
+        Ok(filter) => filter,
+        Err(e) => {
+            log::warn!("Invalid patterns for {}: {}", folder.path, e);
+            return Ok(ReconcileSummary::default());
+        }
+    };
+    let started = Instant::now();
+    let mut summary = ReconcileSummary::default();
+    let mut tx = conn.transaction()?;
+
+    for entry in WalkDir::new(&folder.path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() || !filter.accepts_path(entry.path()) {
+            continue;
+        }
+        summary.scanned += 1;
+        match sync_file(&tx, &filter, entry.path()) {
+            Ok(SyncOutcome::Added) => summary.added += 1,
+            Ok(SyncOutcome::Updated) => summary.updated += 1,
+            Ok(SyncOutcome::Renamed) => summary.renamed += 1,
+            Ok(SyncOutcome::Unchanged) => summary.unchanged += 1,
+            Ok(SyncOutcome::Skipped) => {}
+            Err(e) => log::warn!("Could not sync {}: {}", entry.path().display(), e),
+        }
+        if summary.scanned % BATCH_SIZE == 0 {
+            tx.commit()?;
+            tx = conn.transaction()?;
+        }
+    }
+
+    let (root_id, stored, prefix) = stored_prefix(&tx, &folder.path)?;
+    let known: Vec<String> = {
+        let mut stmt = tx.prepare(
+            "SELECT FullPath FROM Tracks JOIN TrackFiles USING (TrackID)
+            WHERE Missing = 0 AND RootID IS ?1
+                AND (Path = ?2 OR substr(Path, 1, length(?3)) = ?3)",
+        )?;
+        let rows = stmt.query_map(params![root_id, stored, prefix], |row| {
+            row.get::<_, String>(0).map(native_path)
+        })?;
+        rows.collect::<Result<_>>()?
+    };
+    for (checked, path) in known.iter().enumerate() {
+        if !Path::new(path).exists() {
+            summary.missing += mark_missing(&tx, Path::new(path))?;
+        }
+        if (checked + 1) % BATCH_SIZE == 0 {
+            tx.commit()?;
+            tx = conn.transaction()?;
+        }
+    }
+
+    tx.commit()?;
+    summary.elapsed_ms = started.elapsed().as_millis() as u64;
+    Ok(summary)
+}
+
+pub fn load_folders(conn: &Connection) -> Result<Vec<LibraryFolder>> {
+    let mut stmt = conn.prepare(
+        "SELECT FolderID, Path, IncludePatterns, ExcludePatterns, MinDuration FROM LibraryFolders",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        let include: String = row.get(2)?;
+        let exclude: String = row.get(3)?;
+        Ok(LibraryFolder {
+            folder_id: row.get(0)?,
+            path: row.get(1)?,
+            include_patterns: serde_json::from_str(&include).unwrap_or_default(),
+            exclude_patterns: serde_json::from_str(&exclude).unwrap_or_default(),
+            min_duration: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Reconciles every library folder. Returns the summary of all the folders.
+pub fn reconcile_all(conn: &mut Connection) -> Result<ReconcileSummary> {
+    let mut total = ReconcileSummary::default();
+
+    for folder in load_folders(conn)? {
+        match reconcile_folder(conn, &folder) {
+            Ok(summary) => {
+                log::info!(
+                    "{}: {} scanned, {} unchanged, {} added, {} updated, {} renamed, {} missing in {} ms",
+                    folder.path,
+                    summary.scanned,
+                    summary.unchanged,
+                    summary.added,
+                    summary.updated,
+                    summary.renamed,
+                    summary.missing,
+                    summary.elapsed_ms
+                );
+                total.add(&summary);
+            }
+            Err(e) => log::error!("Could not reconcile {}: {}", folder.path, e),
+        }
+    }
+
+    Ok(total)
+}
+
+/// Rescan the library folders: unchanged files (same size and mtime) are skipped,
+/// changed ones are parsed again and renamed ones are recognized by their content hash.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn rescan_library(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<ReconcileSummary, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("rescan_library", None, move |_| {
+        Library::from_pool(&pool)?.rescan_folders()
+    })
+    .await?
+}
+
+/// Get the library folders
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_library_folders(db: State<'_, DbPool>) -> Result<Vec<LibraryFolder>, LibraryError> {
+    Library::from_pool(&db)?.folders()
+}
+
+/// Add a library folder, watch it, and import its files in the background.
+#[tauri::command(rename_all = "snake_case")]
+pub fn add_library_folder(
+    db: State<'_, DbPool>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    folder: LibraryFolder,
+) -> Result<LibraryFolder, LibraryError> {
+    let folder = Library::from_pool(&db)?.add_folder(folder)?;
+
+    watcher.lock().unwrap().watch(&folder.path);
+
+    let added = folder.clone();
+    let pool = db.inner().clone();
+    std::thread::spawn(move || {
+        let result =
+            Library::from_pool(&pool).and_then(|mut library| library.reconcile_folder(&added));
+        if let Err(e) = result {
+            log::error!("Could not import {}: {}", added.path, e);
+        }
+    });
+
+    Ok(folder)
+}
+
+/// Remove a library folder and stop watching it, its tracks stay in the library.
+#[tauri::command(rename_all = "snake_case")]
+pub fn remove_library_folder(
+    db: State<'_, DbPool>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    folder_id: i32,
+) -> Result<(), LibraryError> {
+    let path = Library::from_pool(&db)?.remove_folder(folder_id)?;
+    watcher.lock().unwrap().unwatch(&path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::write_wav;
+    use crate::db::library::Library;
+
+    fn folder(path: &str, include: &[&str], exclude: &[&str], min_duration: u64) -> LibraryFolder {
+        LibraryFolder {
+            folder_id: None,
+            path: path.to_string(),
+            include_patterns: include.iter().map(|p| p.to_string()).collect(),
+            exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+            min_duration,
+        }
+    }
+
+    #[test]
+    fn test_folder_filter_globs() {
+        let filter =
+            FolderFilter::new(&folder("/music", &["**/*.mp3", "*.flac"], &["sfx/**"], 0)).unwrap();
+        assert!(filter.accepts_path(Path::new("/music/Album/01.mp3")));
+        assert!(filter.accepts_path(Path::new("/music/single.flac")));
+        // Excluded, not included, not audio, or outside the folder
+        assert!(!filter.accepts_path(Path::new("/music/sfx/door.mp3")));
+        assert!(!filter.accepts_path(Path::new("/music/Album/01.wav")));
+        assert!(!filter.accepts_path(Path::new("/music/notes.txt")));
+        assert!(!filter.accepts_path(Path::new("/music2/Album/01.mp3")));
+
+        // Without include patterns, every audio file that isn't excluded
+        let filter = FolderFilter::new(&folder("/music", &[], &["**/demo*"], 0)).unwrap();
+        assert!(filter.accepts_path(Path::new("/music/Album/01.wav")));
+        assert!(!filter.accepts_path(Path::new("/music/Album/demo 1.wav")));
+        assert!(!filter.accepts_path(Path::new("/music/cover.jpg")));
+
+        assert!(FolderFilter::new(&folder("/music", &["[a-"], &[], 0)).is_err());
+    }
+
+    #[test]
+    fn test_folder_filter_min_duration() {
+        let dir = std::env::temp_dir().join(format!("rwave-folder-filter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_path = canonical_path(&dir);
+        let short = dir.join("jingle.wav");
+        write_wav(&short, 440.0, "Jingle", "Artist", "Album");
+
+        let mut library = Library::open(":memory:").unwrap();
+        let conn = library.connection();
+        // The fixture lasts a tenth of a second
+        let filter = FolderFilter::new(&folder(&dir_path, &[], &[], 1)).unwrap();
+        assert!(sync_file(conn, &filter, &short).unwrap() == SyncOutcome::Skipped);
+        let filter = FolderFilter::new(&folder(&dir_path, &[], &[], 0)).unwrap();
+        assert!(sync_file(conn, &filter, &short).unwrap() == SyncOutcome::Added);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn track_name(conn: &Connection) -> String {
+        conn.query_row("SELECT Name FROM Tracks", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_unchanged_file_is_not_read_again() {
+        let dir = std::env::temp_dir().join(format!("rwave-unchanged-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.wav");
+        write_wav(&path, 440.0, "Song", "Artist", "Album");
+        let folder = folder(&canonical_path(&dir), &[], &[], 0);
+
+        let mut library = Library::open(":memory:").unwrap();
+        let conn = library.connection();
+        assert_eq!(reconcile_folder(conn, &folder).unwrap().added, 1);
+
+        // Retagged, with the same size and mtime: the tags aren't read, the title stays
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        write_wav(&path, 440.0, "Tune", "Artist", "Album");
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        let summary = reconcile_folder(conn, &folder).unwrap();
+        assert_eq!((summary.unchanged, summary.updated), (1, 0));
+        assert_eq!(track_name(conn), "Song");
+
+        // Once the mtime changes, the file is read again
+        file.set_modified(modified + std::time::Duration::from_secs(60))
+            .unwrap();
+        let summary = reconcile_folder(conn, &folder).unwrap();
+        assert_eq!((summary.unchanged, summary.updated), (0, 1));
+        assert_eq!(track_name(conn), "Tune");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    const FILES: usize = 2_000;
+
+    /// Imports a folder of 2k files with `reconcile_folder`, then rescans it unchanged.
+    /// `cargo test reconcile_folder_latency -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn reconcile_folder_latency() {
+        let dir = std::env::temp_dir().join(format!("rwave-reconcile-{}", std::process::id()));
+        for i in 0..FILES {
+            let album = dir.join(format!("Album {}", i / 10));
+            std::fs::create_dir_all(&album).unwrap();
+            let title = format!("Track {}", i);
+            write_wav(
+                &album.join(format!("{}.wav", i)),
+                440.0,
+                &title,
+                "Artist",
+                "Album",
+            );
+        }
+        let folder = folder(&canonical_path(&dir), &[], &[], 0);
+        let mut library = Library::open(":memory:").unwrap();
+
+        let import = reconcile_folder(library.connection(), &folder).unwrap();
+        assert_eq!(import.added, FILES);
+        let rescan = reconcile_folder(library.connection(), &folder).unwrap();
+        assert_eq!(rescan.unchanged, FILES);
+
+        println!(
+            "{} files: {} ms to import, {} ms to rescan unchanged",
+            FILES, import.elapsed_ms, rescan.elapsed_ms
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
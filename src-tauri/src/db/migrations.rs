@@ -1,47 +1,415 @@
-use tauri_plugin_sql::{Migration, MigrationKind};
-pub fn get_migrations() -> Vec<Migration> {
-    vec![
-        Migration {
-            version: 1,
-            description: "Create Artists Table",
-            sql: "
+use super::{import, roots};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+
+/// One change of the schema, applied in a transaction with `PRAGMA user_version` set to `version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub steps: &'static [Step],
+}
+
+pub enum Step {
+    /// Statements run as a batch. Foreign keys are off while migrating, so a table can be
+    /// rebuilt (new table, copy, drop, rename) without the rows referencing it being touched.
+    Sql(&'static str),
+    /// `ALTER TABLE ... ADD COLUMN` for each column the table doesn't have yet
+    AddColumns(&'static str, &'static [(&'static str, &'static str)]),
+    /// Data backfills that need Rust, e.g. canonical paths
+    Code(fn(&Connection) -> Result<()>),
+}
+
+/// Every migration, in order. Existing steps must never change: add a new migration instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create the Artists, Albums, Tracks and Playlists tables",
+        steps: &[Step::Sql(
+            "
             CREATE TABLE IF NOT EXISTS Artists (
-            ArtistID INTEGER PRIMARY KEY,
-            Name TEXT NOT NULL
+                ArtistID INTEGER PRIMARY KEY,
+                Name TEXT NOT NULL
             );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 2,
-            description: "Create Album Table",
-            sql: "
             CREATE TABLE IF NOT EXISTS Albums (
+                AlbumID INTEGER PRIMARY KEY,
+                Name TEXT NOT NULL,
+                ArtistID INTEGER NOT NULL,
+                FOREIGN KEY(ArtistID) REFERENCES Artists(ArtistID)
+            );
+            CREATE INDEX IF NOT EXISTS idx_Artists_ArtistID ON Artists(ArtistID);
+            CREATE INDEX IF NOT EXISTS idx_Albums_AlbumID ON Albums(AlbumID);
+            CREATE TABLE IF NOT EXISTS Tracks (
+                TrackID INTEGER PRIMARY KEY,
+                Name TEXT NOT NULL,
+                Path TEXT NOT NULL,
+                ArtistID INTEGER NOT NULL,
+                AlbumID INTEGER NOT NULL,
+                Duration INTEGER DEFAULT 0,
+                FOREIGN KEY(ArtistID) REFERENCES Artists(ArtistID),
+                FOREIGN KEY(AlbumID) REFERENCES Albums(AlbumID)
+            );
+            CREATE TABLE IF NOT EXISTS Playlists (
+                PlaylistID INTEGER PRIMARY KEY,
+                Name TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS TrackPlaylist (
+                TrackID INTEGER NOT NULL,
+                PlaylistID INTEGER NOT NULL,
+                PRIMARY KEY(TrackID, PlaylistID),
+                FOREIGN KEY(TrackID) REFERENCES Tracks(TrackID),
+                FOREIGN KEY(PlaylistID) REFERENCES Playlists(PlaylistID)
+            );
+            -- The default playlist, which stores all the tracks
+            INSERT OR IGNORE INTO Playlists (PlaylistID, Name) VALUES (1, 'All Tracks');",
+        )],
+    },
+    Migration {
+        version: 2,
+        description: "Add loop markers (in sample frames) to Tracks",
+        steps: &[Step::AddColumns(
+            "Tracks",
+            &[("LoopStart", "INTEGER"), ("LoopLength", "INTEGER")],
+        )],
+    },
+    Migration {
+        version: 3,
+        description: "Add tag and file properties columns to Tracks",
+        steps: &[Step::AddColumns(
+            "Tracks",
+            &[
+                ("TrackNumber", "INTEGER"),
+                ("DiscNumber", "INTEGER"),
+                ("Year", "INTEGER"),
+                ("Genre", "TEXT"),
+                ("AlbumArtist", "TEXT"),
+                ("Composer", "TEXT"),
+                ("Comment", "TEXT"),
+                ("Bitrate", "INTEGER"),
+                ("SampleRate", "INTEGER"),
+                ("FileSize", "INTEGER"),
+                ("MTime", "INTEGER"),
+            ],
+        )],
+    },
+    Migration {
+        version: 4,
+        description: "Create Artworks and link albums to their artwork",
+        steps: &[
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS Artworks (
+                    ArtworkID INTEGER PRIMARY KEY,
+                    Hash TEXT NOT NULL UNIQUE,
+                    Path TEXT NOT NULL
+                );",
+            ),
+            Step::AddColumns(
+                "Albums",
+                &[("ArtworkID", "INTEGER REFERENCES Artworks(ArtworkID)")],
+            ),
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Create LibraryFolders and track missing files",
+        steps: &[
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS LibraryFolders (
+                    FolderID INTEGER PRIMARY KEY,
+                    Path TEXT NOT NULL UNIQUE,
+                    IncludePatterns TEXT NOT NULL DEFAULT '[]',
+                    ExcludePatterns TEXT NOT NULL DEFAULT '[]',
+                    MinDuration INTEGER NOT NULL DEFAULT 0
+                );",
+            ),
+            Step::AddColumns("Tracks", &[("Missing", "INTEGER NOT NULL DEFAULT 0")]),
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Store the content hash of the tracks, to recognize renamed files",
+        steps: &[
+            Step::AddColumns("Tracks", &[("ContentHash", "TEXT")]),
+            Step::Sql("CREATE INDEX IF NOT EXISTS idx_Tracks_ContentHash ON Tracks(ContentHash);"),
+        ],
+    },
+    Migration {
+        version: 7,
+        description:
+            "Identify tracks by canonical path, merging the rows pointing to the same file",
+        steps: &[Step::Code(identify_tracks_by_path)],
+    },
+    Migration {
+        version: 8,
+        description: "Store the audio hash, play count and rating of the tracks",
+        steps: &[
+            Step::AddColumns(
+                "Tracks",
+                &[
+                    ("AudioHash", "TEXT"),
+                    ("PlayCount", "INTEGER NOT NULL DEFAULT 0"),
+                    ("Rating", "INTEGER"),
+                ],
+            ),
+            Step::Sql("CREATE INDEX IF NOT EXISTS idx_Tracks_AudioHash ON Tracks(AudioHash);"),
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "Store the acoustic fingerprint of the tracks",
+        steps: &[Step::AddColumns("Tracks", &[("Fingerprint", "BLOB")])],
+    },
+    Migration {
+        version: 10,
+        description: "Store the track paths relative to library roots, for portable databases",
+        steps: &[
+            Step::Sql(
+                "
+                CREATE TABLE IF NOT EXISTS LibraryRoots (
+                    RootID INTEGER PRIMARY KEY,
+                    Path TEXT NOT NULL UNIQUE
+                );",
+            ),
+            Step::AddColumns(
+                "Tracks",
+                &[("RootID", "INTEGER REFERENCES LibraryRoots(RootID)")],
+            ),
+            Step::Code(store_paths_relative_to_roots),
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "Delete the playlist memberships of deleted tracks and playlists",
+        steps: &[Step::Sql(
+            "
+            CREATE TABLE TrackPlaylist_new (
+                TrackID INTEGER NOT NULL,
+                PlaylistID INTEGER NOT NULL,
+                PRIMARY KEY(TrackID, PlaylistID),
+                FOREIGN KEY(TrackID) REFERENCES Tracks(TrackID) ON DELETE CASCADE,
+                FOREIGN KEY(PlaylistID) REFERENCES Playlists(PlaylistID) ON DELETE CASCADE
+            );
+            -- Without the memberships left behind before foreign keys were enforced
+            INSERT INTO TrackPlaylist_new (TrackID, PlaylistID)
+                SELECT TrackID, PlaylistID FROM TrackPlaylist
+                WHERE TrackID IN (SELECT TrackID FROM Tracks)
+                AND PlaylistID IN (SELECT PlaylistID FROM Playlists);
+            DROP TABLE TrackPlaylist;
+            ALTER TABLE TrackPlaylist_new RENAME TO TrackPlaylist;",
+        )],
+    },
+];
+
+/// The version of the schema this build uses
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Brings the database up to the latest version, running the missing migrations in order,
+/// each in its own transaction. Must run before anything else uses the database.
+/// Returns the version the database was at.
+pub fn migrate(conn: &mut Connection) -> Result<u32> {
+    let current = current_version(conn)?;
+    if current >= latest_version() {
+        return Ok(current);
+    }
+
+    let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let migrated = run_migrations(conn, current);
+    if foreign_keys {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    }
+    migrated?;
+
+    Ok(current)
+}
+
+fn run_migrations(conn: &mut Connection, current: u32) -> Result<()> {
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!(
+            "Migrating the database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.transaction()?;
+        for step in migration.steps {
+            match step {
+                Step::Sql(sql) => tx.execute_batch(sql)?,
+                Step::AddColumns(table, columns) => {
+                    for (column, definition) in columns.iter() {
+                        add_column_if_missing(&tx, table, column, definition)?;
+                    }
+                }
+                Step::Code(run) => run(&tx)?,
+            }
+        }
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// The version of a database with migrations to run, `None` if it is up to date or empty.
+pub fn pending_migration(conn: &Connection) -> Result<Option<u32>> {
+    let current = current_version(conn)?;
+    let empty = current == 0 && !table_exists(conn, "Tracks")?;
+    Ok((current < latest_version() && !empty).then_some(current))
+}
+
+/// The version of the schema. Databases from before `user_version` was used have 0,
+/// their version is guessed from what created them.
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > 0 || !table_exists(conn, "Tracks")? {
+        return Ok(version);
+    }
+
+    // Created by `tauri_plugin_sql`, whose migrations 1 to 8 are migration 1 here,
+    // and the next ones each one of the next migrations
+    if table_exists(conn, "_sqlx_migrations")? {
+        let applied: Option<u32> = conn
+            .query_row(
+                "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        return Ok(applied.map_or(0, |applied| applied.saturating_sub(7)));
+    }
+
+    // Created by `set_database`, which added whatever was missing on each start. The
+    // migrations can run again on it, except the merge by path of migration 7 once
+    // the paths are relative to roots.
+    match has_column(conn, "Tracks", "RootID")? {
+        true => Ok(9),
+        false => Ok(0),
+    }
+}
+
+pub fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        params![table],
+        |row| row.get(0),
+    )
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    Ok(exists)
+}
+
+/// `ALTER TABLE ... ADD COLUMN` for databases created before the column existed
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+/// Migration 7: rewrites the paths to their canonical form, see `import::canonical_path`,
+/// then merges the rows pointing to the same file into the first one.
+fn identify_tracks_by_path(conn: &Connection) -> Result<()> {
+    canonicalize_track_paths(conn, "SELECT TrackID, Path FROM Tracks")?;
+    conn.execute_batch(
+        "
+        INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID)
+            SELECT (SELECT MIN(Kept.TrackID) FROM Tracks AS Kept WHERE Kept.Path = Tracks.Path),
+                TrackPlaylist.PlaylistID
+            FROM TrackPlaylist JOIN Tracks ON Tracks.TrackID = TrackPlaylist.TrackID;
+        DELETE FROM TrackPlaylist WHERE TrackID NOT IN (SELECT MIN(TrackID) FROM Tracks GROUP BY Path);
+        DELETE FROM Tracks WHERE TrackID NOT IN (SELECT MIN(TrackID) FROM Tracks GROUP BY Path);
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_Tracks_Path ON Tracks(Path);",
+    )
+}
+
+/// Migration 10: a track is identified by its root and path, and the library folders
+/// become roots, so the paths of their tracks are stored relative to them.
+fn store_paths_relative_to_roots(conn: &Connection) -> Result<()> {
+    canonicalize_track_paths(
+        conn,
+        "SELECT TrackID, Path FROM Tracks WHERE RootID IS NULL",
+    )?;
+    conn.execute_batch(
+        "
+        DROP INDEX IF EXISTS idx_Tracks_Path;
+        INSERT OR IGNORE INTO TrackPlaylist (TrackID, PlaylistID)
+            SELECT (SELECT MIN(Kept.TrackID) FROM Tracks AS Kept
+                    WHERE Kept.Path = Tracks.Path AND Kept.RootID IS Tracks.RootID),
+                TrackPlaylist.PlaylistID
+            FROM TrackPlaylist JOIN Tracks ON Tracks.TrackID = TrackPlaylist.TrackID;
+        DELETE FROM TrackPlaylist WHERE TrackID NOT IN
+            (SELECT MIN(TrackID) FROM Tracks GROUP BY Path, IFNULL(RootID, 0));
+        DELETE FROM Tracks WHERE TrackID NOT IN
+            (SELECT MIN(TrackID) FROM Tracks GROUP BY Path, IFNULL(RootID, 0));
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_Tracks_RootPath ON Tracks(Path, IFNULL(RootID, 0));
+        CREATE VIEW IF NOT EXISTS TrackFiles AS
+            SELECT Tracks.TrackID,
+                COALESCE(LibraryRoots.Path || '/' || Tracks.Path, Tracks.Path) AS FullPath
+            FROM Tracks LEFT JOIN LibraryRoots ON LibraryRoots.RootID = Tracks.RootID;",
+    )?;
+
+    let folders: Vec<String> = conn
+        .prepare("SELECT Path FROM LibraryFolders")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    for folder in folders {
+        roots::register_root(conn, &folder)?;
+    }
+    Ok(())
+}
+
+/// A path that would collide with another track's is left for the merge of duplicates.
+fn canonicalize_track_paths(conn: &Connection, select: &str) -> Result<()> {
+    let tracks: Vec<(i64, String)> = conn
+        .prepare(select)?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+
+    for (track_id, path) in tracks {
+        let canonical = import::canonical_path(std::path::Path::new(&path));
+        if canonical != path {
+            conn.execute(
+                "UPDATE OR IGNORE Tracks SET Path = ?1 WHERE TrackID = ?2",
+                params![canonical, track_id],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A library as the first release left it: duplicate rows for one file, in a playlist,
+    /// and the membership of a deleted track
+    const V1_FIXTURE: &str = "
+        PRAGMA foreign_keys = OFF;
+        CREATE TABLE Artists (ArtistID INTEGER PRIMARY KEY, Name TEXT NOT NULL);
+        CREATE TABLE Albums (
             AlbumID INTEGER PRIMARY KEY,
             Name TEXT NOT NULL,
             ArtistID INTEGER NOT NULL,
             FOREIGN KEY(ArtistID) REFERENCES Artists(ArtistID)
-            );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 3,
-            description: "Create ArtistID Index",
-            sql: "CREATE INDEX IF NOT EXISTS idx_Artists_ArtistID ON Artists(ArtistID);",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 4,
-            description: "Create AlbumID Index",
-            sql: "CREATE INDEX IF NOT EXISTS idx_Albums_AlbumID ON Albums(AlbumID);",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 5,
-            description: "Create Tracks Table",
-            sql: "
-            CREATE TABLE IF NOT EXISTS Tracks (
+        );
+        CREATE INDEX idx_Artists_ArtistID ON Artists(ArtistID);
+        CREATE INDEX idx_Albums_AlbumID ON Albums(AlbumID);
+        CREATE TABLE Tracks (
             TrackID INTEGER PRIMARY KEY,
             Name TEXT NOT NULL,
             Path TEXT NOT NULL,
@@ -50,40 +418,106 @@ pub fn get_migrations() -> Vec<Migration> {
             Duration INTEGER DEFAULT 0,
             FOREIGN KEY(ArtistID) REFERENCES Artists(ArtistID),
             FOREIGN KEY(AlbumID) REFERENCES Albums(AlbumID)
-            );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 6,
-            description: "Create Playlists Table",
-            sql: "
-            CREATE TABLE IF NOT EXISTS Playlists (
-            PlaylistID INTEGER PRIMARY KEY,
-            Name TEXT NOT NULL
-            );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 7,
-            description: "Create TrackPlaylist Relationship Table",
-            sql: "
-            CREATE TABLE IF NOT EXISTS TrackPlaylist (
+        );
+        CREATE TABLE Playlists (PlaylistID INTEGER PRIMARY KEY, Name TEXT NOT NULL);
+        CREATE TABLE TrackPlaylist (
             TrackID INTEGER NOT NULL,
             PlaylistID INTEGER NOT NULL,
             PRIMARY KEY(TrackID, PlaylistID),
             FOREIGN KEY(TrackID) REFERENCES Tracks(TrackID),
             FOREIGN KEY(PlaylistID) REFERENCES Playlists(PlaylistID)
-            );
-            ",
-            kind: MigrationKind::Up,
-        },
-        Migration {
-            version: 8,
-            description: "Insert a default playlist `All Tracks`, which would store all tracks",
-            sql: "INSERT OR IGNORE INTO Playlists (PlaylistID, Name) VALUES (1, 'All Tracks');",
-            kind: MigrationKind::Up,
-        },
-    ]
+        );
+        INSERT INTO Playlists (PlaylistID, Name) VALUES (1, 'All Tracks'), (2, 'Favorites');
+        INSERT INTO Artists (ArtistID, Name) VALUES (1, 'Artist');
+        INSERT INTO Albums (AlbumID, Name, ArtistID) VALUES (1, 'Album', 1);
+        INSERT INTO Tracks (TrackID, Name, Path, ArtistID, AlbumID, Duration) VALUES
+            (1, 'One', '/rwave-missing/one.mp3', 1, 1, 180),
+            (2, 'Two', '/rwave-missing/two.mp3', 1, 1, 200),
+            (3, 'One again', '/rwave-missing/one.mp3', 1, 1, 180);
+        INSERT INTO TrackPlaylist (TrackID, PlaylistID) VALUES
+            (1, 1), (2, 1), (3, 1), (3, 2), (4, 1);
+        PRAGMA user_version = 1;";
+
+    #[test]
+    fn upgrades_v1_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(V1_FIXTURE).unwrap();
+
+        assert_eq!(migrate(&mut conn).unwrap(), 1);
+        let version: u32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, latest_version());
+        for column in [
+            "LoopStart",
+            "Genre",
+            "Missing",
+            "ContentHash",
+            "Fingerprint",
+            "RootID",
+        ] {
+            assert!(has_column(&conn, "Tracks", column).unwrap(), "{}", column);
+        }
+        assert!(has_column(&conn, "Albums", "ArtworkID").unwrap());
+
+        // The duplicate row is merged into the first one, with its playlists
+        let tracks: Vec<(i64, String)> = conn
+            .prepare("SELECT TrackID, FullPath FROM TrackFiles ORDER BY TrackID")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            tracks,
+            [
+                (1, "/rwave-missing/one.mp3".to_string()),
+                (2, "/rwave-missing/two.mp3".to_string())
+            ]
+        );
+        let playlists: Vec<(i64, i64)> = conn
+            .prepare("SELECT TrackID, PlaylistID FROM TrackPlaylist ORDER BY TrackID, PlaylistID")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(playlists, [(1, 1), (1, 2), (2, 1)]);
+
+        // Deleting a track or a playlist deletes its memberships
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+            DELETE FROM Tracks WHERE TrackID = 2;
+            DELETE FROM Playlists WHERE PlaylistID = 2;",
+        )
+        .unwrap();
+        let memberships: i64 = conn
+            .query_row("SELECT COUNT(*) FROM TrackPlaylist", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(memberships, 1);
+
+        // Nothing left to do
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn versions_unversioned_databases() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        conn.execute_batch(V1_FIXTURE).unwrap();
+        conn.execute_batch(
+            "PRAGMA user_version = 0;
+            CREATE TABLE _sqlx_migrations (version BIGINT PRIMARY KEY, success BOOLEAN NOT NULL);
+            INSERT INTO _sqlx_migrations (version, success) VALUES (11, 1), (12, 1), (13, 0);",
+        )
+        .unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 5);
+
+        conn.execute_batch("DROP TABLE _sqlx_migrations;").unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        conn.execute_batch("ALTER TABLE Tracks ADD COLUMN RootID INTEGER;")
+            .unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 9);
+    }
 }
@@ -0,0 +1,141 @@
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+/// How long a statement waits for a lock held by another connection before
+/// failing with `SQLITE_BUSY`
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Prepared statements kept by each connection, for `prepare_cached`
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+const MAX_CONNECTIONS: u32 = 8;
+
+/// The connections to the library database, shared by the commands (as Tauri state),
+/// the HTTP server, the watcher and the background jobs. Clones share the same pool,
+/// so they all follow [`DbPool::switch`].
+#[derive(Clone)]
+pub struct DbPool {
+    inner: Arc<RwLock<(PathBuf, Pool<SqliteConnectionManager>)>>,
+}
+
+impl DbPool {
+    pub fn get(&self) -> Result<DbConnection, r2d2::Error> {
+        self.inner.read().unwrap().1.get()
+    }
+
+    /// Path of the database the connections are opened on
+    pub fn path(&self) -> PathBuf {
+        self.inner.read().unwrap().0.clone()
+    }
+
+    /// Opens the connections on the database at `path` from now on.
+    /// Connections already handed out keep the previous database until they are dropped.
+    pub fn switch(&self, path: &Path) -> Result<(), r2d2::Error> {
+        let pool = build_pool(path)?;
+        *self.inner.write().unwrap() = (path.to_path_buf(), pool);
+        Ok(())
+    }
+}
+
+/// Opens the pool on the database at `path`. Every connection uses WAL, so reads
+/// don't wait for a write, and enforces foreign keys.
+pub fn open_pool(path: &Path) -> Result<DbPool, r2d2::Error> {
+    let pool = build_pool(path)?;
+    Ok(DbPool {
+        inner: Arc::new(RwLock::new((path.to_path_buf(), pool))),
+    })
+}
+
+fn build_pool(path: &Path) -> Result<Pool<SqliteConnectionManager>, r2d2::Error> {
+    let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            PRAGMA foreign_keys = ON;",
+        )
+    });
+    Pool::builder().max_size(MAX_CONNECTIONS).build(manager)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::entities::Track;
+    use crate::db::library::Library;
+    use rusqlite::{params, Connection};
+    use std::time::Instant;
+
+    const TRACKS: usize = 10_000;
+    const LOADS: u32 = 20;
+
+    /// Loads a 10k-track playlist as `get_tracks_from_playlist` used to, with a new
+    /// connection and the columns looked up by name for every row, then as it does now.
+    /// `cargo test playlist_load_latency -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn playlist_load_latency() {
+        let path = std::env::temp_dir().join(format!("rwave-pool-{}.db", std::process::id()));
+        let pool = open_pool(&path).unwrap();
+        {
+            let mut conn = pool.get().unwrap();
+            crate::db::migrations::migrate(&mut conn).unwrap();
+            let tx = conn.transaction().unwrap();
+            tx.execute("INSERT INTO Artists (Name) VALUES ('Artist')", ())
+                .unwrap();
+            tx.execute(
+                "INSERT INTO Albums (Name, ArtistID) VALUES ('Album', 1)",
+                (),
+            )
+            .unwrap();
+            for i in 0..TRACKS {
+                tx.execute(
+                    "INSERT INTO Tracks (Name, Path, ArtistID, AlbumID) VALUES (?1, ?2, 1, 1)",
+                    params![format!("Track {}", i), format!("/music/{}.mp3", i)],
+                )
+                .unwrap();
+                tx.execute(
+                    "INSERT INTO TrackPlaylist (TrackID, PlaylistID) VALUES (?, 1)",
+                    params![tx.last_insert_rowid()],
+                )
+                .unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        let started = Instant::now();
+        for _ in 0..LOADS {
+            let conn = Connection::open(&path).unwrap();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT * FROM Tracks JOIN TrackFiles USING (TrackID) JOIN TrackPlaylist
+                    ON Tracks.TrackID = TrackPlaylist.TrackID
+                    WHERE TrackPlaylist.PlaylistID = ?",
+                )
+                .unwrap();
+            let tracks = stmt.query_map([1], Track::from_row).unwrap();
+            assert_eq!(tracks.count(), TRACKS);
+        }
+        let before = started.elapsed() / LOADS;
+
+        let started = Instant::now();
+        for _ in 0..LOADS {
+            let library = Library::from_pool(&pool).unwrap();
+            assert_eq!(library.playlist_tracks(1).unwrap().len(), TRACKS);
+        }
+        let after = started.elapsed() / LOADS;
+
+        println!(
+            "{} tracks: {:?} per load before, {:?} now",
+            TRACKS, before, after
+        );
+        drop(pool);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
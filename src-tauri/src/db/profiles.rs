@@ -0,0 +1,318 @@
+use super::backup::rotate_backup;
+use super::constants::{DB_FILE_NAME, LEGACY_DB_URL};
+use super::library::Library;
+use super::migrations::pending_migration;
+use super::pool::DbPool;
+use super::reload_library;
+use crate::watcher::LibraryWatcher;
+use rusqlite::Connection;
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::State;
+
+const DEFAULT_PROFILE: &str = "Default";
+
+/// A named library, e.g. "Work" or "Home", with its own database.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub database_path: String,
+}
+
+/// The library profiles, persisted as `profiles.json` in the app config directory.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Profiles {
+    /// Name of the profile whose database is open
+    pub active: String,
+    pub profiles: Vec<Profile>,
+}
+
+pub struct ProfileStore {
+    path: PathBuf,
+    data_dir: PathBuf,
+    profiles: Profiles,
+}
+
+impl ProfileStore {
+    /// Load the profiles from `path`. Without one, the default profile is created with
+    /// its database in `data_dir`, taking over the database of earlier versions if any.
+    pub fn load(path: PathBuf, data_dir: PathBuf) -> Self {
+        let profiles = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Profiles>(&content).ok())
+            .filter(|profiles| !profiles.profiles.is_empty());
+
+        let profiles = match profiles {
+            Some(mut profiles) => {
+                if !profiles.profiles.iter().any(|p| p.name == profiles.active) {
+                    profiles.active = profiles.profiles[0].name.clone();
+                }
+                profiles
+            }
+            None => {
+                let database = data_dir.join(DB_FILE_NAME);
+                let config_dir = path.parent().unwrap_or(Path::new("."));
+                adopt_legacy_database(&database, config_dir);
+                Profiles {
+                    active: DEFAULT_PROFILE.to_string(),
+                    profiles: vec![Profile {
+                        name: DEFAULT_PROFILE.to_string(),
+                        database_path: database.to_string_lossy().to_string(),
+                    }],
+                }
+            }
+        };
+
+        ProfileStore {
+            path,
+            data_dir,
+            profiles,
+        }
+    }
+
+    pub fn get(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    pub fn active(&self) -> &Profile {
+        self.find(&self.profiles.active).unwrap()
+    }
+
+    fn find(&self, name: &str) -> Option<&Profile> {
+        self.profiles.profiles.iter().find(|p| p.name == name)
+    }
+
+    fn index(&self, name: &str) -> Result<usize, String> {
+        self.profiles
+            .profiles
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| format!("No profile named {}", name))
+    }
+
+    /// Add a profile. Without `database_path`, its database is `profiles/<name>.db`
+    /// in the app data directory.
+    pub fn add(&mut self, name: &str, database_path: Option<String>) -> Result<Profile, String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("The profile name is empty".to_string());
+        }
+        if self.find(name).is_some() {
+            return Err(format!("A profile named {} already exists", name));
+        }
+        let database_path = database_path.unwrap_or_else(|| {
+            let file_name: String = name
+                .chars()
+                .map(|c| match c.is_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                })
+                .collect();
+            self.data_dir
+                .join("profiles")
+                .join(format!("{}.db", file_name))
+                .to_string_lossy()
+                .to_string()
+        });
+
+        let mut profiles = self.profiles.clone();
+        let profile = Profile {
+            name: name.to_string(),
+            database_path,
+        };
+        profiles.profiles.push(profile.clone());
+        self.save(profiles)?;
+        Ok(profile)
+    }
+
+    /// Remove a profile other than the active one. Its database file is kept.
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        if name == self.profiles.active {
+            return Err("The active profile can't be removed".to_string());
+        }
+        let index = self.index(name)?;
+        let mut profiles = self.profiles.clone();
+        profiles.profiles.remove(index);
+        self.save(profiles)
+    }
+
+    pub fn set_database_path(&mut self, name: &str, database_path: String) -> Result<(), String> {
+        let index = self.index(name)?;
+        let mut profiles = self.profiles.clone();
+        profiles.profiles[index].database_path = database_path;
+        self.save(profiles)
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<(), String> {
+        self.index(name)?;
+        let mut profiles = self.profiles.clone();
+        profiles.active = name.to_string();
+        self.save(profiles)
+    }
+
+    /// Replace the profiles and write them to disk
+    fn save(&mut self, profiles: Profiles) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let content = serde_json::to_string_pretty(&profiles).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, content).map_err(|e| e.to_string())?;
+
+        self.profiles = profiles;
+        Ok(())
+    }
+}
+
+/// Copies the database of earlier versions to `database`, if it doesn't exist yet.
+/// The file `rusqlite` created in the working directory is preferred, as the
+/// commands wrote to it, then the one `tauri_plugin_sql` opened in `config_dir`.
+fn adopt_legacy_database(database: &Path, config_dir: &Path) {
+    if database.exists() {
+        return;
+    }
+    let legacy = [PathBuf::from(LEGACY_DB_URL), config_dir.join(DB_FILE_NAME)];
+    let Some(legacy) = legacy.iter().find(|path| path.is_file()) else {
+        return;
+    };
+    let copied = database
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::copy(legacy, database));
+    match copied {
+        Ok(_) => log::info!(
+            "Copied the database {} to {}",
+            legacy.display(),
+            database.display()
+        ),
+        Err(e) => log::error!("Could not copy the database {}: {}", legacy.display(), e),
+    }
+}
+
+/// Creates the database at `path` if needed and migrates it to the latest schema,
+/// before the pool opens it. A database is backed up before it is migrated.
+pub fn prepare_database(path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let conn = Connection::open(path).map_err(|e| e.to_string())?;
+    if let Some(version) = pending_migration(&conn).map_err(|e| e.to_string())? {
+        let backup = rotate_backup(&conn, path, version).map_err(|e| e.to_string())?;
+        log::info!("Backed up {} to {}", path.display(), backup.display());
+    }
+    drop(conn);
+    Library::open(path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Opens the library at `path` in place of the current one: the commands, the HTTP
+/// server and the artwork protocol use it from now on, and its folders are watched and rescanned.
+fn open_library(db: &DbPool, watcher: &Mutex<LibraryWatcher>, path: &Path) -> Result<(), String> {
+    prepare_database(path)?;
+    db.switch(path).map_err(|e| e.to_string())?;
+    reload_library(db, watcher);
+    Ok(())
+}
+
+/// Get the library profiles and the active one
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_profiles(profiles: State<'_, Mutex<ProfileStore>>) -> Profiles {
+    profiles.lock().unwrap().get().clone()
+}
+
+/// Add a library profile, with its database at `database_path` or in the app data directory.
+#[tauri::command(rename_all = "snake_case")]
+pub fn create_profile(
+    profiles: State<'_, Mutex<ProfileStore>>,
+    name: String,
+    database_path: Option<String>,
+) -> Result<Profile, String> {
+    profiles.lock().unwrap().add(&name, database_path)
+}
+
+/// Remove a library profile, its database file is kept.
+#[tauri::command(rename_all = "snake_case")]
+pub fn delete_profile(
+    profiles: State<'_, Mutex<ProfileStore>>,
+    name: String,
+) -> Result<(), String> {
+    profiles.lock().unwrap().remove(&name)
+}
+
+/// Move the database of a profile to `database_path`, e.g. a synced folder.
+/// The file itself isn't moved; the library there is opened if the profile is active.
+#[tauri::command(rename_all = "snake_case")]
+pub fn set_profile_database(
+    profiles: State<'_, Mutex<ProfileStore>>,
+    db: State<'_, DbPool>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    name: String,
+    database_path: String,
+) -> Result<(), String> {
+    let mut profiles = profiles.lock().unwrap();
+    if name == profiles.get().active {
+        open_library(&db, &watcher, Path::new(&database_path))?;
+    }
+    profiles.set_database_path(&name, database_path)
+}
+
+/// Switch to another library profile. The frontend must load the database again,
+/// from `get_database_url`.
+#[tauri::command(rename_all = "snake_case")]
+pub fn switch_profile(
+    profiles: State<'_, Mutex<ProfileStore>>,
+    db: State<'_, DbPool>,
+    watcher: State<'_, Mutex<LibraryWatcher>>,
+    name: String,
+) -> Result<Profile, String> {
+    let mut profiles = profiles.lock().unwrap();
+    let profile = profiles.get().profiles[profiles.index(&name)?].clone();
+    open_library(&db, &watcher, Path::new(&profile.database_path))?;
+    profiles.set_active(&name)?;
+    Ok(profile)
+}
+
+/// The URL of the open database for `tauri_plugin_sql`, e.g. `sqlite:/home/me/.local/share/com.rwave.release/rwave.db`
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_database_url(db: State<'_, DbPool>) -> String {
+    format!("sqlite:{}", db.path().display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_are_persisted() {
+        let dir = std::env::temp_dir().join(format!("rwave-profiles-{}", std::process::id()));
+        let path = dir.join("config").join("profiles.json");
+        let data_dir = dir.join("data");
+
+        let mut store = ProfileStore::load(path.clone(), data_dir.clone());
+        assert_eq!(store.active().name, DEFAULT_PROFILE);
+        assert_eq!(
+            Path::new(&store.active().database_path),
+            data_dir.join(DB_FILE_NAME)
+        );
+
+        let work = store.add("Work / Office", None).unwrap();
+        assert_eq!(
+            Path::new(&work.database_path),
+            data_dir.join("profiles").join("Work___Office.db")
+        );
+        assert!(store.add("Work / Office", None).is_err());
+        store
+            .add("Home", Some("/music/home.db".to_string()))
+            .unwrap();
+        store.set_active("Home").unwrap();
+        assert!(store.remove("Home").is_err());
+        store.remove("Work / Office").unwrap();
+
+        let store = ProfileStore::load(path, data_dir);
+        assert_eq!(store.active().name, "Home");
+        assert_eq!(store.active().database_path, "/music/home.db");
+        assert_eq!(store.get().profiles.len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
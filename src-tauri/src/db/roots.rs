@@ -0,0 +1,330 @@
+use super::entities::LibraryRoot;
+use super::error::LibraryError;
+use super::library::Library;
+use super::pool::DbPool;
+use rusqlite::{params, Connection, Result};
+use std::path::{Path, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use tauri::State;
+
+/// Separator of the stored relative paths, whatever the platform, so they resolve anywhere.
+/// `TrackFiles.FullPath` joins the root and the relative path with it too.
+const SEPARATOR: &str = "/";
+
+/// The library roots, innermost first.
+pub fn load_roots(conn: &Connection) -> Result<Vec<LibraryRoot>> {
+    let mut stmt =
+        conn.prepare_cached("SELECT RootID, Path FROM LibraryRoots ORDER BY length(Path) DESC")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(LibraryRoot {
+            root_id: row.get(0)?,
+            path: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// `path` relative to `root` with `/` separators, if it is `root` or inside it.
+fn relative_to(root: &str, path: &str) -> Option<String> {
+    let rest = path.strip_prefix(root.trim_end_matches(MAIN_SEPARATOR))?;
+    if rest.is_empty() {
+        return Some(String::new());
+    }
+    Some(
+        rest.strip_prefix(MAIN_SEPARATOR)?
+            .replace(MAIN_SEPARATOR, SEPARATOR),
+    )
+}
+
+/// How the absolute `path` is stored in `Tracks`: relative to the innermost root
+/// containing it, or as is, without a root, if no root does.
+pub fn store_path(conn: &Connection, path: &str) -> Result<(Option<i64>, String)> {
+    for root in load_roots(conn)? {
+        if let Some(relative) = relative_to(&root.path, path) {
+            return Ok((root.root_id, relative));
+        }
+    }
+    Ok((None, path.to_string()))
+}
+
+/// Matches the tracks stored inside the folder `dir`, for a `Tracks` query ending with
+/// `RootID IS ?1 AND (Path = ?2 OR substr(Path, 1, length(?3)) = ?3)`.
+pub fn stored_prefix(conn: &Connection, dir: &str) -> Result<(Option<i64>, String, String)> {
+    let (root_id, stored) = store_path(conn, dir)?;
+    let prefix = match (root_id, stored.is_empty()) {
+        // The root itself, every track of the root
+        (Some(_), true) => String::new(),
+        (Some(_), false) => format!("{}{}", stored, SEPARATOR),
+        (None, _) => format!(
+            "{}{}",
+            stored.trim_end_matches(MAIN_SEPARATOR),
+            MAIN_SEPARATOR
+        ),
+    };
+    Ok((root_id, stored, prefix))
+}
+
+/// A path read from `TrackFiles.FullPath`, with the separators of the platform.
+pub fn native_path(path: String) -> String {
+    match MAIN_SEPARATOR_STR == SEPARATOR {
+        true => path,
+        false => path.replace(SEPARATOR, MAIN_SEPARATOR_STR),
+    }
+}
+
+/// Registers `path` as a library root, unless a root already contains it.
+/// The tracks stored with an absolute path inside it, and the roots inside it,
+/// are moved under the new root. Returns the id of the root containing `path`.
+pub fn register_root(conn: &Connection, path: &str) -> Result<i64> {
+    if let (Some(root_id), _) = store_path(conn, path)? {
+        return Ok(root_id);
+    }
+    conn.execute("INSERT INTO LibraryRoots (Path) VALUES (?)", params![path])?;
+    let root_id = conn.last_insert_rowid();
+
+    for inner in load_roots(conn)? {
+        let Some(relative) = relative_to(path, &inner.path) else {
+            continue;
+        };
+        if inner.root_id != Some(root_id) {
+            conn.execute(
+                "UPDATE Tracks SET RootID = ?1, Path = ?2 || Path WHERE RootID = ?3",
+                params![root_id, format!("{}{}", relative, SEPARATOR), inner.root_id],
+            )?;
+            conn.execute(
+                "DELETE FROM LibraryRoots WHERE RootID = ?",
+                params![inner.root_id],
+            )?;
+        }
+    }
+
+    let absolute: Vec<(i64, String)> = conn
+        .prepare("SELECT TrackID, Path FROM Tracks WHERE RootID IS NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    for (track_id, track_path) in absolute {
+        if let Some(relative) = relative_to(path, &track_path) {
+            conn.execute(
+                "UPDATE Tracks SET RootID = ?1, Path = ?2 WHERE TrackID = ?3",
+                params![root_id, relative, track_id],
+            )?;
+        }
+    }
+
+    Ok(root_id)
+}
+
+/// Points a root to where it is on this machine, e.g. after the database was copied
+/// from another computer. The library folders inside the root follow it.
+pub fn move_root(conn: &mut Connection, root_id: i64, new_path: &str) -> Result<()> {
+    let tx = conn.transaction()?;
+    let old_path: String = tx.query_row(
+        "SELECT Path FROM LibraryRoots WHERE RootID = ?",
+        params![root_id],
+        |row| row.get(0),
+    )?;
+    tx.execute(
+        "UPDATE LibraryRoots SET Path = ?1 WHERE RootID = ?2",
+        params![new_path, root_id],
+    )?;
+
+    let folders: Vec<(i64, String)> = tx
+        .prepare("SELECT FolderID, Path FROM LibraryFolders")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    for (folder_id, folder_path) in folders {
+        if let Some(relative) = relative_to(&old_path, &folder_path) {
+            let moved = relative
+                .split(SEPARATOR)
+                .filter(|component| !component.is_empty())
+                .fold(Path::new(new_path).to_path_buf(), |path, component| {
+                    path.join(component)
+                });
+            tx.execute(
+                "UPDATE LibraryFolders SET Path = ?1 WHERE FolderID = ?2",
+                params![moved.to_string_lossy(), folder_id],
+            )?;
+        }
+    }
+
+    tx.commit()
+}
+
+/// Get the library roots, the folders the paths of the tracks are relative to.
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_library_roots(db: State<'_, DbPool>) -> Result<Vec<LibraryRoot>, LibraryError> {
+    Library::from_pool(&db)?.roots()
+}
+
+/// Register a library root, e.g. the music folder of a drive. The tracks already
+/// inside it are stored relative to it from then on.
+#[tauri::command(rename_all = "snake_case")]
+pub fn add_library_root(db: State<'_, DbPool>, path: String) -> Result<i64, LibraryError> {
+    Library::from_pool(&db)?.add_root(&path)
+}
+
+/// Point a library root to its folder on this machine, which relinks all its tracks.
+#[tauri::command(rename_all = "snake_case")]
+pub fn set_library_root_path(
+    db: State<'_, DbPool>,
+    root_id: i64,
+    path: String,
+) -> Result<(), LibraryError> {
+    Library::from_pool(&db)?.move_root(root_id, &path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::library::Library;
+
+    /// `path` with the separators of the platform
+    fn native(path: &str) -> String {
+        path.replace('/', MAIN_SEPARATOR_STR)
+    }
+
+    fn library() -> Library {
+        let mut library = Library::open(":memory:").unwrap();
+        library
+            .connection()
+            .execute_batch(
+                "INSERT INTO Artists (ArtistID, Name) VALUES (1, 'Artist');
+                INSERT INTO Albums (AlbumID, Name, ArtistID) VALUES (1, 'Album', 1);",
+            )
+            .unwrap();
+        library
+    }
+
+    fn add_root(conn: &Connection, path: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO LibraryRoots (Path) VALUES (?)",
+            params![native(path)],
+        )
+        .unwrap();
+        conn.last_insert_rowid()
+    }
+
+    fn add_track(conn: &Connection, root_id: Option<i64>, path: &str) -> i64 {
+        conn.execute(
+            "INSERT INTO Tracks (Name, RootID, Path, ArtistID, AlbumID)
+            VALUES ('Track', ?1, ?2, 1, 1)",
+            params![root_id, path],
+        )
+        .unwrap();
+        conn.last_insert_rowid()
+    }
+
+    fn stored(conn: &Connection, track_id: i64) -> (Option<i64>, String) {
+        conn.query_row(
+            "SELECT RootID, Path FROM Tracks WHERE TrackID = ?",
+            params![track_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    fn full_path(conn: &Connection, track_id: i64) -> String {
+        conn.query_row(
+            "SELECT FullPath FROM TrackFiles WHERE TrackID = ?",
+            params![track_id],
+            |row| row.get(0).map(native_path),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_relative_to() {
+        let relative = |root: &str, path: &str| relative_to(&native(root), &native(path));
+        assert_eq!(
+            relative("/music", "/music/Rock/a.mp3").as_deref(),
+            Some("Rock/a.mp3")
+        );
+        assert_eq!(
+            relative("/music/", "/music/a.mp3").as_deref(),
+            Some("a.mp3")
+        );
+        assert_eq!(relative("/music", "/music").as_deref(), Some(""));
+        // Only the start of the name, not a folder inside the root
+        assert_eq!(relative("/music", "/music2/a.mp3"), None);
+        assert_eq!(relative("/music", "/other/a.mp3"), None);
+    }
+
+    #[test]
+    fn test_store_path() {
+        let mut library = library();
+        let conn = library.connection();
+        let music = add_root(conn, "/music");
+        let rock = add_root(conn, "/music/Rock");
+
+        let store = |path: &str| store_path(conn, &native(path)).unwrap();
+        // The innermost root containing the path
+        assert_eq!(
+            store("/music/Rock/a.mp3"),
+            (Some(rock), "a.mp3".to_string())
+        );
+        assert_eq!(
+            store("/music/Jazz/Live/b.mp3"),
+            (Some(music), "Jazz/Live/b.mp3".to_string())
+        );
+        assert_eq!(store("/music2/c.mp3"), (None, native("/music2/c.mp3")));
+    }
+
+    #[test]
+    fn test_register_root_merges_nested_roots() {
+        let mut library = library();
+        let conn = library.connection();
+        let rock = add_root(conn, "/music/Rock");
+        let in_rock = add_track(conn, Some(rock), "Album/a.mp3");
+        let absolute = add_track(conn, None, &native("/music/Jazz/b.mp3"));
+        let outside = add_track(conn, None, &native("/music2/c.mp3"));
+
+        let music = register_root(conn, &native("/music")).unwrap();
+        // The inner root and its tracks move under the new one
+        let roots: Vec<String> = load_roots(conn)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(roots, [native("/music")]);
+        assert_eq!(
+            stored(conn, in_rock),
+            (Some(music), "Rock/Album/a.mp3".to_string())
+        );
+        assert_eq!(
+            stored(conn, absolute),
+            (Some(music), "Jazz/b.mp3".to_string())
+        );
+        assert_eq!(stored(conn, outside), (None, native("/music2/c.mp3")));
+        assert_eq!(full_path(conn, in_rock), native("/music/Rock/Album/a.mp3"));
+
+        // A folder inside a root is already covered
+        assert_eq!(register_root(conn, &native("/music/Pop")).unwrap(), music);
+        assert_eq!(load_roots(conn).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_move_root() {
+        let mut library = library();
+        let conn = library.connection();
+        let root = add_root(conn, "/old/music");
+        let track = add_track(conn, Some(root), "Rock/a.mp3");
+        conn.execute(
+            "INSERT INTO LibraryFolders (Path, IncludePatterns, ExcludePatterns, MinDuration)
+            VALUES (?1, '[]', '[]', 0), (?2, '[]', '[]', 0)",
+            params![native("/old/music/Rock"), native("/old/musical")],
+        )
+        .unwrap();
+
+        move_root(conn, root, &native("/new/music")).unwrap();
+        assert_eq!(full_path(conn, track), native("/new/music/Rock/a.mp3"));
+        let folders: Vec<String> = conn
+            .prepare("SELECT Path FROM LibraryFolders ORDER BY FolderID")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(folders, [native("/new/music/Rock"), native("/old/musical")]);
+
+        assert!(move_root(conn, root + 1, &native("/new/music")).is_err());
+    }
+}
@@ -1,57 +1,113 @@
-// use super::entities::Track;
-use super::{constants::*, Album, Artist};
-use rusqlite::{params, Connection};
+use super::error::LibraryError;
+use super::library::Library;
+use super::pool::DbPool;
+use super::utils::Chapter;
+use super::{Album, Artist};
+use crate::decoder::AudioInfo;
+use crate::jobs::JobRegistry;
+use crate::metadata::{FieldChange, TagChanges};
+use serde_derive::Serialize;
+use tauri::State;
 
 /// Get album by id
 #[tauri::command(rename_all = "snake_case")]
-pub fn get_album(album_id: i32) -> Album {
-    let conn = Connection::open(DB_URL).unwrap();
-
-    let mut stmt = conn
-        .prepare("SELECT * FROM Albums WHERE AlbumID = ?")
-        .unwrap();
-    let album_row = stmt.query_row(params![album_id], |row| {
-        Ok(Album {
-            album_id: row.get(0)?,
-            name: row.get(1)?,
-            artist_id: row.get(2)?,
+pub fn get_album(db: State<'_, DbPool>, album_id: i32) -> Result<Album, LibraryError> {
+    Library::from_pool(&db)?.album(album_id)
+}
+
+/// Get artist by id
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_artist(db: State<'_, DbPool>, artist_id: i32) -> Result<Artist, LibraryError> {
+    Library::from_pool(&db)?.artist(artist_id)
+}
+
+/// Get the ID3 chapters of a track by id
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_chapters(db: State<'_, DbPool>, track_id: i32) -> Result<Vec<Chapter>, LibraryError> {
+    Library::from_pool(&db)?.chapters(track_id)
+}
+
+/// Get the codec, container and stream properties of a track by id
+#[tauri::command(rename_all = "snake_case")]
+pub fn get_audio_info(db: State<'_, DbPool>, track_id: i32) -> Result<AudioInfo, LibraryError> {
+    Library::from_pool(&db)?.audio_info(track_id)
+}
+
+/// Recompute the duration of every track stored without one (`0` or `NULL`),
+/// such as VBR MP3 files imported before their length could be read from the headers.
+/// Returns the number of tracks updated. When cancelled, the tracks done so far are kept.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn backfill_durations(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<usize, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("backfill_durations", None, move |job| {
+        Library::from_pool(&pool)?.backfill_durations(job.cancel_flag(), |done, total| {
+            job.progress(done, Some(total))
+        })
+    })
+    .await?
+}
+
+/// Read the tags and file properties of every track again, filling in the track/disc number,
+/// year, genre, album artist, composer, comment, bitrate, sample rate, size and mtime columns.
+/// Returns the number of tracks updated, files that can't be read are skipped.
+/// When cancelled, the tracks done so far are kept.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn rescan_track_details(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<usize, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("rescan_track_details", None, move |job| {
+        Library::from_pool(&pool)?.rescan_track_details(job.cancel_flag(), |done, total| {
+            job.progress(done, Some(total))
         })
-    });
+    })
+    .await?
+}
 
-    match album_row {
-        Ok(album) => return album,
-        Err(_) => {
-            return Album {
-                album_id: None,
-                name: "Unknown".to_string(),
-                artist_id: None,
-            }
-        }
-    }
+/// The changes `update_track_tags` makes, or would make, to one file.
+#[derive(Debug, Serialize)]
+pub struct TrackTagDiff {
+    pub track_id: i64,
+    pub path: String,
+    pub changes: Vec<FieldChange>,
 }
 
-/// Get album by id
+/// Write tag changes to the files of several tracks, and update `Tracks`/`Artists`/`Albums`
+/// to match. With `dry_run`, nothing is written and the per-file diff is returned.
+/// Either every file and row is updated, or, on the first failure, the files written so far
+/// are restored and the database transaction is rolled back.
 #[tauri::command(rename_all = "snake_case")]
-pub fn get_artist(artist_id: i32) -> Artist {
-    let conn = Connection::open(DB_URL).unwrap();
+pub async fn update_track_tags(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+    track_ids: Vec<i64>,
+    changes: TagChanges,
+    dry_run: bool,
+) -> Result<Vec<TrackTagDiff>, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("update_track_tags", None, move |_| {
+        Library::from_pool(&pool)?.update_track_tags(&track_ids, &changes, dry_run)
+    })
+    .await?
+}
 
-    let mut stmt = conn
-        .prepare("SELECT * FROM Artists WHERE ArtistID = ?")
-        .unwrap();
-    let artist_row = stmt.query_row(params![artist_id], |row| {
-        Ok(Artist {
-            artist_id: row.get(0)?,
-            name: row.get(1)?,
+/// Look for the artwork of every album that has none, in the tags of its tracks
+/// or next to them. Returns the number of albums that got an artwork.
+/// When cancelled, the albums done so far keep theirs.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn rescan_artwork(
+    db: State<'_, DbPool>,
+    jobs: State<'_, JobRegistry>,
+) -> Result<usize, LibraryError> {
+    let pool = db.inner().clone();
+    jobs.run("rescan_artwork", None, move |job| {
+        Library::from_pool(&pool)?.rescan_artwork(job.cancel_flag(), |done, total| {
+            job.progress(done, Some(total))
         })
-    });
-
-    match artist_row {
-        Ok(artist) => return artist,
-        Err(_) => {
-            return Artist {
-                artist_id: None,
-                name: "Unknown Artist".to_string(),
-            }
-        }
-    }
+    })
+    .await?
 }
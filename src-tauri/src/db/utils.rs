@@ -1,37 +1,173 @@
-use id3::{Tag, TagLike};
-use rodio::{source::Source, Decoder};
-use std::fs::File;
-use std::io::BufReader;
-
-/// Parses the tags from an MP3 file.
-/// Returns the title, artist, and album name.
-pub fn parse_mp3_tags(
-    path: &str,
-) -> Result<(Option<String>, Option<String>, Option<String>, Option<u64>), Box<dyn std::error::Error>>
-{
-    let tag = Tag::read_from_path(path)?;
-    let file = BufReader::new(File::open(path).unwrap());
-    let source = Decoder::new(file).unwrap();
-
-    Ok((
-        tag.title().map(|s| s.to_string()),
-        tag.artist().map(|s| s.to_string()),
-        tag.album().map(|s| s.to_string()),
-        source.total_duration().map(|d| d.as_secs()),
-    ))
+use crate::decoder::probe_duration;
+use id3::Tag;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Reads the duration of any supported track file, in seconds, without decoding audio.
+pub fn probe_track_duration(path: &str) -> Option<u64> {
+    if crate::midi::is_midi_file(path) {
+        crate::midi::parse_midi_tags(path).ok()?.duration
+    } else {
+        probe_duration(path).map(|d| d.as_secs())
+    }
+}
+
+/// A chapter read from an ID3v2 `CHAP` frame.
+/// Start and end are in milliseconds from the beginning of the track.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chapter {
+    pub element_id: String,
+    pub title: Option<String>,
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+/// Parses the `CHAP`/`CTOC` frames from an MP3 file.
+/// Chapters listed by the top level `CTOC` frame come first, in its order,
+/// any chapter not referenced by a table of contents is appended by start time.
+pub fn parse_chapters(path: &str) -> Result<Vec<Chapter>, Box<dyn std::error::Error>> {
+    let tag = match id3::no_tag_ok(Tag::read_from_path(path))? {
+        Some(tag) => tag,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut ordered_ids = Vec::new();
+    let mut visited = HashSet::new();
+    for toc in tag.tables_of_contents().filter(|toc| toc.top_level) {
+        collect_toc_elements(&tag, &toc.element_id, &mut visited, &mut ordered_ids);
+    }
+
+    let mut chapters: Vec<&id3::frame::Chapter> = ordered_ids
+        .iter()
+        .filter_map(|id| tag.chapters().find(|c| &c.element_id == id))
+        .collect();
+    let mut unlisted: Vec<&id3::frame::Chapter> = tag
+        .chapters()
+        .filter(|c| !ordered_ids.contains(&c.element_id))
+        .collect();
+    unlisted.sort_by_key(|c| c.start_time);
+    chapters.extend(unlisted);
+
+    Ok(chapters
+        .into_iter()
+        .map(|c| Chapter {
+            element_id: c.element_id.clone(),
+            title: c
+                .frames
+                .iter()
+                .find(|f| f.id() == "TIT2")
+                .and_then(|f| f.content().text())
+                .map(|s| s.to_string()),
+            start_ms: c.start_time as u64,
+            end_ms: c.end_time as u64,
+        })
+        .collect())
+}
+
+/// Walks a `CTOC` frame and its nested tables, pushing chapter ids in order.
+fn collect_toc_elements(
+    tag: &Tag,
+    element_id: &str,
+    visited: &mut HashSet<String>,
+    ordered_ids: &mut Vec<String>,
+) {
+    // Guard against malformed tags where tables reference each other
+    if !visited.insert(element_id.to_string()) {
+        return;
+    }
+
+    match tag
+        .tables_of_contents()
+        .find(|toc| toc.element_id == element_id)
+    {
+        Some(toc) => {
+            for child in &toc.elements {
+                collect_toc_elements(tag, child, visited, ordered_ids);
+            }
+        }
+        None => ordered_ids.push(element_id.to_string()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::parse_chapters;
+    use crate::db::fixtures::write_wav_lasting;
+    use crate::metadata::read_track_metadata;
+    use id3::frame::{Chapter, TableOfContents};
+    use id3::{Frame, Tag, TagLike, Version};
+
+    fn chapter(element_id: &str, start_time: u32, end_time: u32) -> Chapter {
+        Chapter {
+            element_id: element_id.to_string(),
+            start_time,
+            end_time,
+            start_offset: u32::MAX,
+            end_offset: u32::MAX,
+            frames: Vec::new(),
+        }
+    }
+
+    fn table_of_contents(element_id: &str, top_level: bool, elements: &[&str]) -> TableOfContents {
+        TableOfContents {
+            element_id: element_id.to_string(),
+            top_level,
+            ordered: true,
+            elements: elements.iter().map(|e| e.to_string()).collect(),
+            frames: Vec::new(),
+        }
+    }
 
     #[test]
-    fn test_parse_mp3_tags() {
-        let path = "F:/workspace/rwavetest/西木康智 - 洞窟ダンジョン.mp3";
-        let (title, artist, album, duration) = parse_mp3_tags(path).unwrap();
-        println!("Title: {:?}", title);
-        println!("Artist: {:?}", artist);
-        println!("Album: {:?}", album);
-        println!("Duration: {:?}", duration);
+    fn test_parse_chapters() {
+        let path = std::env::temp_dir().join(format!("rwave-chapters-{}.mp3", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+
+        let mut tag = Tag::new();
+        let mut intro = chapter("intro", 0, 3000);
+        intro.frames.push(Frame::text("TIT2", "Intro"));
+        tag.add_frame(intro);
+        tag.add_frame(chapter("verse", 3000, 6000));
+        tag.add_frame(chapter("outro", 9000, 12000));
+        tag.add_frame(chapter("bonus", 6000, 9000));
+        // The table of contents lists the verse before the intro, through a nested table
+        // that refers back to the top level one
+        tag.add_frame(table_of_contents("toc", true, &["verse", "part"]));
+        tag.add_frame(table_of_contents("part", false, &["intro", "toc"]));
+        tag.write_to_path(&path, Version::Id3v24).unwrap();
+
+        let chapters = parse_chapters(&path.to_string_lossy()).unwrap();
+        let ids: Vec<&str> = chapters.iter().map(|c| c.element_id.as_str()).collect();
+        // Unlisted chapters come last, by start time
+        assert_eq!(ids, ["verse", "intro", "bonus", "outro"]);
+        assert_eq!(chapters[1].title.as_deref(), Some("Intro"));
+        assert_eq!(chapters[0].title, None);
+        assert_eq!((chapters[3].start_ms, chapters[3].end_ms), (9000, 12000));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_chapters_without_tag() {
+        let path = std::env::temp_dir().join(format!("rwave-no-tag-{}.mp3", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        assert!(parse_chapters(&path.to_string_lossy()).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let path = std::env::temp_dir().join(format!("rwave-tags-{}.wav", std::process::id()));
+        let duration = std::time::Duration::from_secs(2);
+        write_wav_lasting(&path, duration, 440.0, "Cave Dungeon", "Artist", "Album");
+
+        let metadata = read_track_metadata(&path.to_string_lossy()).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Cave Dungeon"));
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.duration, Some(2));
+        assert_eq!(metadata.sample_rate, Some(8000));
+
+        std::fs::remove_file(path).unwrap();
     }
 }
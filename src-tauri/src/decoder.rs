@@ -0,0 +1,440 @@
+use rodio::{source::SeekError, Source};
+use serde_derive::Serialize;
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use symphonia::core::audio::{Channels, SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{CodecRegistry, Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+/// Opens `path` with the symphonia probe, returning the format reader of the file.
+pub fn probe(path: &str) -> Result<Box<dyn FormatReader>, SymphoniaError> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &format_options,
+        &MetadataOptions::default(),
+    )?;
+
+    Ok(probed.format)
+}
+
+/// Symphonia's codecs, plus the libopus decoder symphonia lacks.
+pub fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<crate::opus::OpusDecoder>();
+        registry
+    })
+}
+
+/// The first track of the file that carries audio.
+pub fn audio_track(format: &dyn FormatReader) -> Result<&Track, SymphoniaError> {
+    format
+        .default_track()
+        .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .or_else(|| {
+            format
+                .tracks()
+                .iter()
+                .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        })
+        .ok_or(SymphoniaError::Unsupported("no audio track"))
+}
+
+fn track_duration(track: &Track) -> Option<Duration> {
+    let params = &track.codec_params;
+    let n_frames = params.n_frames?;
+
+    match params.time_base {
+        Some(time_base) => {
+            let time = time_base.calc_time(n_frames);
+            Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+        }
+        None => Some(Duration::from_secs_f64(
+            n_frames as f64 / params.sample_rate? as f64,
+        )),
+    }
+}
+
+fn is_mp3_file(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("mp3"))
+        .unwrap_or(false)
+}
+
+/// Reads the duration of an audio file from its container, without decoding any audio.
+/// MP3 files go through `mp3::probe_mp3_duration` first, symphonia only estimates the
+/// length of VBR files that lack a Xing/VBRI header.
+pub fn probe_duration(path: &str) -> Option<Duration> {
+    if is_mp3_file(path) {
+        if let Some(duration) = crate::mp3::probe_mp3_duration(path) {
+            return Some(duration);
+        }
+    }
+
+    let format = probe(path).ok()?;
+    track_duration(audio_track(format.as_ref()).ok()?)
+}
+
+/// Codec and stream properties of an audio file, as reported by `get_audio_info`.
+#[derive(Serialize)]
+pub struct AudioInfo {
+    pub codec: String,
+    pub container: String,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u32>,
+    /// Average bitrate over the whole file, in kbit/s
+    pub bitrate: Option<u32>,
+    /// Duration in seconds
+    pub duration: Option<u64>,
+}
+
+/// Container name, symphonia doesn't report which format reader it picked.
+fn container_name(path: &str) -> String {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "mp3" | "mp2" | "mp1" => "MPEG audio",
+        "m4a" | "m4b" | "mp4" | "alac" => "MP4",
+        "flac" => "FLAC",
+        "ogg" | "oga" | "opus" => "Ogg",
+        "wav" | "wave" => "WAV",
+        "aif" | "aiff" | "aifc" => "AIFF",
+        "caf" => "CAF",
+        "mka" | "mkv" | "webm" => "Matroska",
+        _ => "Unknown",
+    }
+    .to_string()
+}
+
+/// Probes `path` and reports its codec, container and stream properties.
+pub fn probe_audio_info(path: &str) -> Result<AudioInfo, SymphoniaError> {
+    let format = probe(path)?;
+    let track = audio_track(format.as_ref())?;
+    let params = &track.codec_params;
+
+    let codec = codecs()
+        .get_codec(params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let duration = match is_mp3_file(path) {
+        true => crate::mp3::probe_mp3_duration(path).or_else(|| track_duration(track)),
+        false => track_duration(track),
+    };
+    let file_size = std::fs::metadata(path)?.len();
+    let bitrate = duration
+        .filter(|d| !d.is_zero())
+        .map(|d| (file_size as f64 * 8.0 / d.as_secs_f64() / 1000.0).round() as u32);
+
+    Ok(AudioInfo {
+        codec,
+        container: container_name(path),
+        sample_rate: params.sample_rate,
+        bit_depth: params.bits_per_sample.or(params.bits_per_coded_sample),
+        channels: params.channels.map(|channels| channels.count() as u32),
+        bitrate,
+        duration: duration.map(|d| d.as_secs()),
+    })
+}
+
+/// A `Source` decoding any format supported by symphonia, with sample-accurate seeking.
+pub struct SymphoniaSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    spec: SignalSpec,
+    buffer: SampleBuffer<f32>,
+    /// Index of the next sample to emit from `buffer`
+    position: usize,
+    /// Samples to drop from the next decoded packets, to land exactly on a seek target
+    skip: usize,
+    time_base: Option<TimeBase>,
+    total_duration: Option<Duration>,
+}
+
+impl SymphoniaSource {
+    pub fn open(path: &str) -> Result<Self, SymphoniaError> {
+        let format = probe(path)?;
+        let track = audio_track(format.as_ref())?;
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let total_duration = match is_mp3_file(path) {
+            true => crate::mp3::probe_mp3_duration(path).or_else(|| track_duration(track)),
+            false => track_duration(track),
+        };
+        let decoder = codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+        let mut source = SymphoniaSource {
+            format,
+            decoder,
+            track_id,
+            spec: SignalSpec::new(0, Default::default()),
+            // Replaced by the first packet; `SampleBuffer::new` divides by the channel count
+            buffer: SampleBuffer::new(0, SignalSpec::new(0, Channels::FRONT_LEFT)),
+            position: 0,
+            skip: 0,
+            time_base,
+            total_duration,
+        };
+
+        // Decode the first packet, so the channels and the sample rate are known
+        if !source.decode_next_packet()? {
+            return Err(SymphoniaError::DecodeError("no audio packet"));
+        }
+
+        Ok(source)
+    }
+
+    /// Decodes the next packet of the track into `buffer`.
+    /// Returns `false` at the end of the stream.
+    fn decode_next_packet(&mut self) -> Result<bool, SymphoniaError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt packet is skipped rather than ending playback
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let spec = *decoded.spec();
+            let frames = decoded.capacity();
+            if spec != self.spec || self.buffer.capacity() < frames * spec.channels.count() {
+                self.buffer = SampleBuffer::new(frames as u64, spec);
+                self.spec = spec;
+            }
+            self.buffer.copy_interleaved_ref(decoded);
+            self.position = 0;
+
+            // Drop the head of the packet if it lies before the seek target
+            if self.skip > 0 {
+                let skipped = self.skip.min(self.buffer.samples().len());
+                self.position = skipped;
+                self.skip -= skipped;
+                if self.position == self.buffer.samples().len() {
+                    continue;
+                }
+            }
+
+            return Ok(true);
+        }
+    }
+
+    /// Decodes the next packet once the current one is used up, so `current_frame_len`
+    /// never reports an empty frame while there is audio left.
+    /// Returns `false` at the end of the stream.
+    fn refill(&mut self) -> bool {
+        if self.position < self.buffer.samples().len() {
+            return true;
+        }
+
+        match self.decode_next_packet() {
+            Ok(more) => more,
+            Err(e) => {
+                log::warn!("Decoding stopped: {}", e);
+                self.buffer.clear();
+                self.position = 0;
+                false
+            }
+        }
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if !self.refill() {
+            return None;
+        }
+
+        let sample = self.buffer.samples()[self.position];
+        self.position += 1;
+        self.refill();
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        // The stream parameters may change between packets
+        Some(self.buffer.samples().len() - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let seeked_to = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(pos),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| SeekError::Other(Box::new(e)))?;
+        self.decoder.reset();
+
+        // The format reader lands on a packet boundary at or before the target,
+        // the frames in between are decoded and dropped
+        let delta = seeked_to.required_ts.saturating_sub(seeked_to.actual_ts);
+        let frames = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(delta);
+                ((time.seconds as f64 + time.frac) * self.spec.rate as f64).round() as usize
+            }
+            None => delta as usize,
+        };
+        self.skip = frames * self.spec.channels.count();
+        self.buffer.clear();
+        self.position = 0;
+        self.refill();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures;
+
+    /// Half a second of a 440 Hz mono tone, encoded with the reference encoders
+    fn fixture(name: &str) -> String {
+        format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Decodes the whole file, checking it holds a mono 440 Hz tone.
+    /// Returns the sample rate and the number of frames.
+    fn decode_tone(path: &str) -> (u32, usize) {
+        let mut source = SymphoniaSource::open(path).unwrap();
+        assert_eq!(source.channels(), 1, "{}", path);
+        let rate = source.sample_rate();
+
+        // Times the rising edges with some hysteresis, lossy codecs add noise around zero.
+        // The encoder delay may leave silence at either end, only the tone itself is timed.
+        let mut edges = Vec::new();
+        let (mut frames, mut peak, mut negative) = (0, 0f32, false);
+        for sample in source.by_ref() {
+            peak = peak.max(sample.abs());
+            if sample < -0.05 {
+                negative = true;
+            } else if sample > 0.05 && negative {
+                edges.push(frames);
+                negative = false;
+            }
+            frames += 1;
+        }
+
+        assert!(peak > 0.1, "{} decodes to silence", path);
+        let span = (edges[edges.len() - 1] - edges[0]) as f32 / rate as f32;
+        let frequency = (edges.len() - 1) as f32 / span;
+        assert!(
+            (frequency - 440.0).abs() < 5.0,
+            "{}: {} Hz",
+            path,
+            frequency
+        );
+        (rate, frames)
+    }
+
+    #[test]
+    fn test_decode_lossless() {
+        for (name, codec) in [("tone.flac", "flac"), ("tone-alac.m4a", "alac")] {
+            assert_eq!(decode_tone(&fixture(name)), (44100, 22050), "{}", name);
+            assert_eq!(probe_audio_info(&fixture(name)).unwrap().codec, codec);
+        }
+
+        let dir = std::env::temp_dir().join(format!("rwave-decoder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let wav = dir.join("tone.wav");
+        fixtures::write_wav(&wav, 440.0, "Tone", "Artist", "Album");
+        assert_eq!(decode_tone(wav.to_str().unwrap()), (8000, 800));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_decode_lossy() {
+        // Encoders pad the stream, not all containers say by how much
+        for (name, codec, rate) in [
+            ("tone.mp3", "mp3", 44100),
+            ("tone.ogg", "vorbis", 44100),
+            ("tone.opus", "opus", 48000),
+            ("tone-aac.m4a", "aac", 44100),
+        ] {
+            let (decoded_rate, frames) = decode_tone(&fixture(name));
+            assert_eq!(decoded_rate, rate, "{}", name);
+            let expected = rate as usize / 2;
+            assert!(
+                frames.abs_diff(expected) < expected / 20,
+                "{}: {} frames",
+                name,
+                frames
+            );
+            assert_eq!(probe_audio_info(&fixture(name)).unwrap().codec, codec);
+        }
+    }
+
+    #[test]
+    fn test_opus_pre_skip() {
+        let (rate, frames) = decode_tone(&fixture("tone.opus"));
+        assert_eq!((rate, frames), (48000, 24000));
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut source = SymphoniaSource::open(&fixture("tone.flac")).unwrap();
+        source.try_seek(Duration::from_millis(250)).unwrap();
+        assert_eq!(source.count(), 22050 - 11025);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::decoder::SymphoniaSource;
+use rodio::Source;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+/// Audio is downmixed and resampled to this rate before the analysis
+const SAMPLE_RATE: u32 = 11025;
+/// Only the start of the track is fingerprinted, enough to tell recordings apart
+const MAX_SECONDS: u32 = 120;
+const FRAME_SIZE: usize = 4096;
+/// Frames overlap by two thirds
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+/// Range of the frequencies folded into the chroma, in Hz
+const MIN_FREQUENCY: f32 = 28.0;
+const MAX_FREQUENCY: f32 = 3520.0;
+/// Offset between two fingerprints tried by `similarity`, in frames (about 0.12 s each),
+/// to absorb the encoder delay and the silence trimmed by some rips
+const MAX_OFFSET: isize = 16;
+
+/// A Chromaprint-style acoustic fingerprint: one 32-bit word per frame, describing
+/// how the energy of the 12 pitch classes compares across notes and over time.
+/// Close for the same recording in different encodes (MP3 128k, 320k, FLAC...).
+pub fn fingerprint(path: &str) -> Option<Vec<u32>> {
+    if crate::midi::is_midi_file(path) {
+        return None;
+    }
+
+    let samples = mono_samples(path)?;
+    let chroma = chromagram(&samples);
+    if chroma.len() < 2 {
+        return None;
+    }
+
+    Some(
+        chroma
+            .windows(2)
+            .map(|pair| sub_fingerprint(&pair[0], &pair[1]))
+            .collect(),
+    )
+}
+
+/// Decodes the start of the file, downmixed to mono and resampled to `SAMPLE_RATE`.
+fn mono_samples(path: &str) -> Option<Vec<f32>> {
+    let source = SymphoniaSource::open(path).ok()?;
+    let channels = source.channels().max(1) as usize;
+    let step = source.sample_rate() as f64 / SAMPLE_RATE as f64;
+    let max_samples = (SAMPLE_RATE * MAX_SECONDS) as usize;
+
+    // Nearest-sample resampling is enough here, the chroma only keeps pitch classes
+    let mut samples = Vec::with_capacity(max_samples);
+    let mut frame = Vec::with_capacity(channels);
+    let mut input_frame = 0usize;
+    for sample in source {
+        frame.push(sample);
+        if frame.len() < channels {
+            continue;
+        }
+        let mono = frame.iter().sum::<f32>() / channels as f32;
+        frame.clear();
+
+        while (samples.len() as f64 * step) as usize <= input_frame {
+            samples.push(mono);
+            if samples.len() >= max_samples {
+                return Some(samples);
+            }
+        }
+        input_frame += 1;
+    }
+
+    Some(samples)
+}
+
+/// The normalized energy of each pitch class, frame by frame.
+fn chromagram(samples: &[f32]) -> Vec<[f32; 12]> {
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32).cos()
+        })
+        .collect();
+
+    // Pitch class of every FFT bin within the range, A = 0
+    let bin_classes: Vec<Option<usize>> = (0..FRAME_SIZE / 2)
+        .map(|bin| {
+            let frequency = bin as f32 * SAMPLE_RATE as f32 / FRAME_SIZE as f32;
+            (MIN_FREQUENCY..=MAX_FREQUENCY)
+                .contains(&frequency)
+                .then(|| (12.0 * (frequency / 440.0).log2()).round().rem_euclid(12.0) as usize)
+        })
+        .collect();
+
+    let mut buffer = vec![Complex::new(0.0f32, 0.0); FRAME_SIZE];
+    let mut chroma = Vec::new();
+    let mut start = 0;
+    while start + FRAME_SIZE <= samples.len() {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(samples[start + i] * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let mut classes = [0f32; 12];
+        for (bin, class) in bin_classes.iter().enumerate() {
+            if let Some(class) = class {
+                classes[*class] += buffer[bin].norm_sqr();
+            }
+        }
+        let norm = classes
+            .iter()
+            .map(|energy| energy * energy)
+            .sum::<f32>()
+            .sqrt();
+        if norm > 0.0 {
+            classes.iter_mut().for_each(|energy| *energy /= norm);
+        }
+        chroma.push(classes);
+
+        start += HOP_SIZE;
+    }
+
+    chroma
+}
+
+/// 12 bits comparing each pitch class with the next one, 12 bits telling whether
+/// each class gained energy since the previous frame, and 8 comparing classes a third apart.
+fn sub_fingerprint(previous: &[f32; 12], current: &[f32; 12]) -> u32 {
+    let mut bits = 0u32;
+    for i in 0..12 {
+        bits = (bits << 1) | (current[i] > current[(i + 1) % 12]) as u32;
+    }
+    for i in 0..12 {
+        bits = (bits << 1) | (current[i] > previous[i]) as u32;
+    }
+    for i in 0..8 {
+        bits = (bits << 1) | (current[i] > current[i + 4]) as u32;
+    }
+    bits
+}
+
+/// Share of the bits two fingerprints have in common, from 0 to 1, at the best offset.
+/// Unrelated recordings land around 0.5, encodes of the same one above 0.8.
+pub fn similarity(a: &[u32], b: &[u32]) -> f32 {
+    let min_overlap = a.len().min(b.len()) / 2;
+    if min_overlap == 0 {
+        return 0.0;
+    }
+
+    (-MAX_OFFSET..=MAX_OFFSET)
+        .filter_map(|offset| {
+            let (a, b) = match offset < 0 {
+                true => (a.get(offset.unsigned_abs()..)?, b),
+                false => (a, b.get(offset as usize..)?),
+            };
+            let overlap = a.len().min(b.len());
+            if overlap < min_overlap {
+                return None;
+            }
+            let differing: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+            Some(1.0 - differing as f32 / (overlap * 32) as f32)
+        })
+        .fold(0.0, f32::max)
+}
+
+/// Packs a fingerprint for the `Tracks.Fingerprint` column.
+pub fn to_bytes(fingerprint: &[u32]) -> Vec<u8> {
+    fingerprint
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect()
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::write_wav_lasting;
+    use std::time::Duration;
+
+    #[test]
+    fn test_similarity() {
+        let a: Vec<u32> = (0..400u32).map(|i| i.wrapping_mul(2654435761)).collect();
+
+        // The same words, a few frames later and with some bits flipped
+        let mut b = vec![0u32; 5];
+        b.extend(a.iter().map(|word| word ^ 0b1));
+        assert!(similarity(&a, &b) > 0.95);
+
+        let c: Vec<u32> = (0..400u32)
+            .map(|i| i.wrapping_mul(40503) ^ 0x5bd1e995)
+            .collect();
+        assert!(similarity(&a, &c) < 0.7);
+    }
+
+    #[test]
+    fn test_fingerprint_tones() {
+        let dir = std::env::temp_dir().join(format!("rwave-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let duration = Duration::from_secs(5);
+        write_wav_lasting(&dir.join("a.wav"), duration, 440.0, "A", "Artist", "Album");
+        write_wav_lasting(&dir.join("e.wav"), duration, 330.0, "E", "Artist", "Album");
+
+        // The same tone with 8-bit samples, as a lossy copy would lose the details
+        let mut wav = std::fs::read(dir.join("a.wav")).unwrap();
+        let data = wav.windows(4).rposition(|id| id == b"data").unwrap() + 8;
+        for sample in wav[data..].chunks_exact_mut(2) {
+            sample[0] = 0;
+        }
+        std::fs::write(dir.join("a-8bit.wav"), wav).unwrap();
+
+        let of = |name: &str| fingerprint(&dir.join(name).to_string_lossy()).unwrap();
+        let (a, a_8bit, e) = (of("a.wav"), of("a-8bit.wav"), of("e.wav"));
+        assert!(similarity(&a, &a_8bit) > 0.8);
+        assert!(similarity(&a, &e) < 0.8);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let fingerprint = vec![0, 1, u32::MAX, 0xdeadbeef];
+        assert_eq!(from_bytes(&to_bytes(&fingerprint)), fingerprint);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::decoder::{audio_track, probe};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use symphonia::core::errors::Error as SymphoniaError;
+
+/// Bytes read from the start, the middle and the end of large files
+const SAMPLE_SIZE: u64 = 256 * 1024;
+
+/// A quick hash of the content of a file, used to recognize a track after a rename.
+/// Files larger than three samples are hashed from their size and three samples
+/// instead of in full, which keeps rescans of large libraries fast.
+pub fn content_hash(path: &str) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    if size <= SAMPLE_SIZE * 3 {
+        std::io::copy(&mut file, &mut hasher)?;
+    } else {
+        let mut sample = vec![0u8; SAMPLE_SIZE as usize];
+        for offset in [0, size / 2 - SAMPLE_SIZE / 2, size - SAMPLE_SIZE] {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut sample)?;
+            hasher.update(&sample);
+        }
+    }
+
+    Ok(to_hex(hasher))
+}
+
+/// Whether the file at `path` has the content `hash`, as given by `content_hash`.
+/// The hash of a large file only covers three samples of it, which an edited file may
+/// share: it is then confirmed with the hash of the audio, `audio`, and never matches
+/// without it.
+pub fn has_content(path: &str, hash: &str, audio: Option<&str>) -> bool {
+    if content_hash(path).ok().as_deref() != Some(hash) {
+        return false;
+    }
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() <= SAMPLE_SIZE * 3 => true,
+        Ok(_) => audio.is_some_and(|audio| audio_hash(path).as_deref() == Some(audio)),
+        Err(_) => false,
+    }
+}
+
+/// A hash of the audio packets of a file, leaving its tags out, so copies of a file
+/// that were tagged differently share it. MIDI files keep their names in their events,
+/// they are hashed whole. `None` if the file can't be demuxed.
+pub fn audio_hash(path: &str) -> Option<String> {
+    if crate::midi::is_midi_file(path) {
+        return content_hash(path).ok();
+    }
+
+    let mut format = probe(path).ok()?;
+    let track_id = audio_track(format.as_ref()).ok()?.id;
+
+    let mut hasher = Sha256::new();
+    let mut packets = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                hasher.update(&packet.data);
+                packets += 1;
+            }
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(_) => return None,
+        }
+    }
+
+    (packets > 0).then(|| to_hex(hasher))
+}
+
+fn to_hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::fixtures::{write_wav, write_wav_lasting};
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn test_has_content() {
+        let dir = std::env::temp_dir().join(format!("rwave-hashing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        // Hashed in full, the content hash is enough
+        write_wav(dir.join("short.wav").as_path(), 440.0, "Short", "A", "B");
+        let short = path("short.wav");
+        let hash = content_hash(&short).unwrap();
+        assert!(has_content(&short, &hash, None));
+        assert!(!has_content(&short, "0", None));
+
+        // A minute of audio is hashed from samples, which miss an edit between them
+        let long = dir.join("long.wav");
+        write_wav_lasting(&long, Duration::from_secs(60), 440.0, "Long", "A", "B");
+        std::fs::copy(&long, dir.join("edited.wav")).unwrap();
+        let mut edited = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join("edited.wav"))
+            .unwrap();
+        edited.seek(SeekFrom::Start(SAMPLE_SIZE + 1024)).unwrap();
+        edited.write_all(&[0x7f; 64]).unwrap();
+        drop(edited);
+
+        let (long, edited) = (path("long.wav"), path("edited.wav"));
+        let hash = content_hash(&long).unwrap();
+        let audio = audio_hash(&long).unwrap();
+        assert_eq!(content_hash(&edited).unwrap(), hash);
+        assert!(has_content(&long, &hash, Some(&audio)));
+        assert!(!has_content(&long, &hash, None));
+        assert!(!has_content(&edited, &hash, Some(&audio)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use serde_derive::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, State};
+
+/// Name of the event every `JobEvent` is emitted as
+pub const JOB_EVENT: &str = "job";
+
+/// Least time between two progress events of a job
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A running job, as listed by `list_jobs`.
+#[derive(Clone, Serialize)]
+pub struct JobInfo {
+    pub job_id: String,
+    /// The command running it, e.g. `import_folder`
+    pub kind: String,
+    /// Milliseconds since the Unix epoch
+    pub started_at: u64,
+    pub done: usize,
+    /// `None` until the amount of work is known
+    pub total: Option<usize>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    Started(JobInfo),
+    Progress {
+        job_id: String,
+        done: usize,
+        total: Option<usize>,
+    },
+    /// `error` is set if the job failed
+    Finished {
+        job_id: String,
+        cancelled: bool,
+        error: Option<String>,
+    },
+}
+
+struct Job {
+    info: JobInfo,
+    cancel: Arc<AtomicBool>,
+}
+
+/// The long-running library commands in flight. Their work runs on the blocking
+/// thread pool, so the UI stays responsive, and their changes are emitted as `JOB_EVENT`.
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    /// Where the events go, `None` in tests
+    app: Option<AppHandle>,
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+}
+
+/// What the work of a job gets, to report its progress and notice it was cancelled.
+pub struct JobContext {
+    job_id: String,
+    cancel: Arc<AtomicBool>,
+    registry: JobRegistry,
+    last_progress: Mutex<Option<Instant>>,
+}
+
+impl JobContext {
+    /// The flag `cancel_job` sets, for functions taking an `&AtomicBool`
+    pub fn cancel_flag(&self) -> &AtomicBool {
+        &self.cancel
+    }
+
+    /// Records that `done` of `total` items are processed. Events are throttled,
+    /// except the last one.
+    pub fn progress(&self, done: usize, total: Option<usize>) {
+        if let Some(job) = self.registry.jobs.lock().unwrap().get_mut(&self.job_id) {
+            job.info.done = done;
+            job.info.total = total;
+        }
+
+        let mut last_progress = self.last_progress.lock().unwrap();
+        let due = last_progress.map_or(true, |last| last.elapsed() >= PROGRESS_INTERVAL);
+        if due || Some(done) == total {
+            *last_progress = Some(Instant::now());
+            self.registry.emit(JobEvent::Progress {
+                job_id: self.job_id.clone(),
+                done,
+                total,
+            });
+        }
+    }
+}
+
+impl JobRegistry {
+    pub fn new(app: AppHandle) -> Self {
+        JobRegistry {
+            app: Some(app),
+            jobs: Default::default(),
+        }
+    }
+
+    fn emit(&self, event: JobEvent) {
+        if let Some(app) = &self.app {
+            if let Err(e) = app.emit(JOB_EVENT, event) {
+                log::warn!("Could not emit a job event: {}", e);
+            }
+        }
+    }
+
+    /// Runs `work` on the blocking thread pool as the job `job_id`, a new id if `None`,
+    /// and waits for it. Fails like `spawn_blocking` if the work panicked, and without
+    /// running it if a job with this id is already running.
+    pub async fn run<T, E, F>(
+        &self,
+        kind: &str,
+        job_id: Option<String>,
+        work: F,
+    ) -> tauri::Result<Result<T, E>>
+    where
+        T: Send + 'static,
+        E: Display + Send + 'static,
+        F: FnOnce(&JobContext) -> Result<T, E> + Send + 'static,
+    {
+        let job_id = job_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let info = JobInfo {
+            job_id: job_id.clone(),
+            kind: kind.to_string(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis() as u64),
+            done: 0,
+            total: None,
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        match self.jobs.lock().unwrap().entry(job_id.clone()) {
+            // Two jobs with one id couldn't be told apart, nor cancelled separately
+            Entry::Occupied(_) => {
+                return Err(tauri::Error::Io(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("Job {} is already running", job_id),
+                )))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Job {
+                    info: info.clone(),
+                    cancel: cancel.clone(),
+                });
+            }
+        }
+        self.emit(JobEvent::Started(info));
+
+        let context = JobContext {
+            job_id: job_id.clone(),
+            cancel: cancel.clone(),
+            registry: self.clone(),
+            last_progress: Mutex::new(None),
+        };
+        let result = tauri::async_runtime::spawn_blocking(move || work(&context)).await;
+
+        self.jobs.lock().unwrap().remove(&job_id);
+        let error = match &result {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(e) => Some(e.to_string()),
+        };
+        self.emit(JobEvent::Finished {
+            job_id,
+            cancelled: cancel.load(Ordering::Relaxed),
+            error,
+        });
+        result
+    }
+
+    /// The running jobs, oldest first
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs: Vec<JobInfo> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| job.info.clone())
+            .collect();
+        jobs.sort_by_key(|job| job.started_at);
+        jobs
+    }
+
+    /// Asks a job to stop. Returns `false` if no job has this id.
+    pub fn cancel(&self, job_id: &str) -> bool {
+        match self.jobs.lock().unwrap().get(job_id) {
+            Some(job) => {
+                job.cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Get the running jobs, for an activity indicator. Changes come as `job` events.
+#[tauri::command(rename_all = "snake_case")]
+pub fn list_jobs(jobs: State<'_, JobRegistry>) -> Vec<JobInfo> {
+    jobs.list()
+}
+
+/// Stop a running job. What it did until then is kept.
+/// Returns `false` if no job has this id.
+#[tauri::command(rename_all = "snake_case")]
+pub fn cancel_job(jobs: State<'_, JobRegistry>, job_id: String) -> bool {
+    jobs.cancel(&job_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn lists_and_cancels_jobs() {
+        let registry = JobRegistry::default();
+        let (started, wait_started) = mpsc::channel();
+
+        let running = registry.clone();
+        let job = tauri::async_runtime::spawn(async move {
+            running
+                .run("count", Some("job-1".to_string()), move |job| {
+                    job.progress(1, Some(3));
+                    started.send(()).unwrap();
+                    while !job.cancel_flag().load(Ordering::Relaxed) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    Ok::<_, String>(job.cancel_flag().load(Ordering::Relaxed))
+                })
+                .await
+        });
+
+        wait_started.recv().unwrap();
+        let jobs = registry.list();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].kind, "count");
+        assert_eq!((jobs[0].done, jobs[0].total), (1, Some(3)));
+
+        let again = registry.run("count", Some("job-1".to_string()), |_| {
+            Ok::<_, String>(true)
+        });
+        assert!(tauri::async_runtime::block_on(again).is_err());
+        assert_eq!(registry.list().len(), 1);
+
+        assert!(!registry.cancel("job-2"));
+        assert!(registry.cancel("job-1"));
+        let result = tauri::async_runtime::block_on(job).unwrap().unwrap();
+        assert!(result.unwrap());
+        assert!(registry.list().is_empty());
+    }
+}
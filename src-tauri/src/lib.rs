@@ -1,26 +1,36 @@
+mod artwork;
 mod commands;
 mod db;
+mod decoder;
+mod fingerprint;
+mod hashing;
+mod jobs;
+mod looping;
+mod metadata;
+mod midi;
+mod mp3;
+mod opus;
 mod player;
+mod settings;
+mod watcher;
 
+use db::pool::DbPool;
+use std::path::Path;
 use std::sync::Mutex;
 use tauri::Manager;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let player = player::Player::spawn();
-    let migrations = crate::db::migrations::get_migrations();
-
-    db::db_start();
 
     tauri::Builder::default()
-        .plugin(
-            tauri_plugin_sql::Builder::new()
-                .add_migrations("sqlite:rwave.db", migrations)
-                .build(),
-        )
+        .plugin(tauri_plugin_sql::Builder::new().build())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
-        .setup(|app| {
+        .register_uri_scheme_protocol(artwork::URI_SCHEME, |ctx, request| {
+            artwork::handle_request(&ctx.app_handle().state::<DbPool>(), request)
+        })
+        .setup(move |app| {
             if cfg!(debug_assertions) {
                 app.handle().plugin(
                     tauri_plugin_log::Builder::default()
@@ -28,17 +38,44 @@ pub fn run() {
                         .build(),
                 )?;
             }
+            let profiles = db::profiles::ProfileStore::load(
+                app.path().app_config_dir()?.join("profiles.json"),
+                app.path().app_data_dir()?,
+            );
+            let database = profiles.active().database_path.clone();
+            // Migrated before anything else opens it
+            db::profiles::prepare_database(Path::new(&database))?;
+            let pool = db::pool::open_pool(Path::new(&database))?;
+            db::db_start(pool.clone());
+            app.manage(Mutex::new(profiles));
+
+            artwork::set_artwork_dir(app.path().app_data_dir()?.join("artwork"));
+            let settings_store =
+                settings::SettingsStore::load(app.path().app_config_dir()?.join("settings.json"));
+            player.apply_settings(settings_store.get().clone());
+            app.manage(Mutex::new(settings_store));
             app.manage(Mutex::new(player));
+            app.manage(jobs::JobRegistry::new(app.handle().clone()));
+            app.manage(pool.clone());
+
+            // Watch first, so changes made during the reconciliation aren't lost
+            app.manage(Mutex::new(watcher::LibraryWatcher::spawn(pool.clone())));
+            db::refresh_library(pool);
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             commands::load_track,
+            commands::enqueue_track,
             commands::play_track,
             commands::pause_track,
             commands::seek_track,
+            commands::next_chapter,
+            commands::previous_chapter,
             commands::subscribe_player_event,
             commands::unsubscribe_player_event,
             commands::parse_mp3_tags_command,
+            settings::get_settings,
+            settings::update_settings,
             db::playlistcommands::get_tracks_from_playlist,
             db::playlistcommands::create_playlist,
             db::playlistcommands::delete_playlist,
@@ -49,6 +86,39 @@ pub fn run() {
             db::playlistcommands::add_track_command,
             db::trackcommands::get_album,
             db::trackcommands::get_artist,
+            db::trackcommands::get_chapters,
+            db::trackcommands::get_audio_info,
+            db::trackcommands::backfill_durations,
+            db::trackcommands::rescan_track_details,
+            db::trackcommands::update_track_tags,
+            db::trackcommands::rescan_artwork,
+            db::importcommands::import_folder,
+            jobs::list_jobs,
+            jobs::cancel_job,
+            db::libraryfolders::get_library_folders,
+            db::libraryfolders::add_library_folder,
+            db::libraryfolders::remove_library_folder,
+            db::libraryfolders::rescan_library,
+            db::duplicates::find_duplicates,
+            db::duplicates::merge_duplicates,
+            db::duplicates::fingerprint_library,
+            db::duplicates::find_similar_recordings,
+            db::health::check_library_health,
+            db::health::relocate_missing_tracks,
+            db::health::rewrite_path_prefix,
+            db::health::vacuum_library,
+            db::backup::backup_library,
+            db::backup::restore_library,
+            db::backup::check_library,
+            db::roots::get_library_roots,
+            db::roots::add_library_root,
+            db::roots::set_library_root_path,
+            db::profiles::get_profiles,
+            db::profiles::create_profile,
+            db::profiles::delete_profile,
+            db::profiles::set_profile_database,
+            db::profiles::switch_profile,
+            db::profiles::get_database_url,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,437 @@
+use id3::Tag;
+use rodio::{source::SeekError, Sample, Source};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// Loop markers of a track, in sample frames from the beginning of the track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopPoints {
+    pub start: u64,
+    pub length: u64,
+}
+
+/// Reads the `LOOPSTART`/`LOOPLENGTH` (or `LOOP_START`/`LOOP_END`) markers of a track.
+/// The markers are looked up in ID3 `TXXX` frames, then in Vorbis comments (FLAC, Ogg Vorbis, Opus).
+pub fn read_loop_points(path: &str) -> Option<LoopPoints> {
+    if let Ok(tag) = Tag::read_from_path(path) {
+        let comments = tag
+            .extended_texts()
+            .map(|t| (t.description.to_uppercase(), t.value.clone()))
+            .collect();
+        if let Some(points) = loop_points_from_comments(&comments) {
+            return Some(points);
+        }
+    }
+
+    let comments = read_vorbis_comments(path).ok()?;
+    loop_points_from_comments(&comments)
+}
+
+fn loop_points_from_comments(comments: &HashMap<String, String>) -> Option<LoopPoints> {
+    let get = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| comments.get(*key))
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+
+    let start = get(&["LOOPSTART", "LOOP_START"])?;
+    let length = match get(&["LOOPLENGTH", "LOOP_LENGTH"]) {
+        Some(length) => length,
+        None => get(&["LOOPEND", "LOOP_END"])?.checked_sub(start)?,
+    };
+
+    if length == 0 {
+        return None;
+    }
+
+    Some(LoopPoints { start, length })
+}
+
+/// Reads the Vorbis comments of a FLAC, Ogg Vorbis or Opus file.
+/// Keys are upper-cased, as Vorbis comment keys are case-insensitive.
+fn read_vorbis_comments(path: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    // FLAC files are sometimes prefixed by an ID3v2 tag, skip over it
+    if &magic[..3] == b"ID3" {
+        let mut header = [0u8; 6];
+        file.read_exact(&mut header)?;
+        let size = header[2..6]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+        let footer = if header[1] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer))?;
+        file.read_exact(&mut magic)?;
+    }
+
+    let block = match &magic {
+        b"fLaC" => read_flac_comment_block(&mut file)?,
+        b"OggS" => read_ogg_comment_packet(&mut file)?,
+        _ => return Err("no Vorbis comments in file".into()),
+    };
+
+    parse_vorbis_comment_block(&block)
+}
+
+/// Returns the body of the `VORBIS_COMMENT` metadata block of a FLAC stream.
+fn read_flac_comment_block<R: Read + Seek>(
+    file: &mut R,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        if block_type == 4 {
+            let mut block = vec![0u8; length];
+            file.read_exact(&mut block)?;
+            return Ok(block);
+        }
+        if is_last {
+            return Err("no VORBIS_COMMENT block".into());
+        }
+        file.seek(SeekFrom::Current(length as i64))?;
+    }
+}
+
+/// Returns the comment header packet of an Ogg Vorbis or Opus stream,
+/// without its `\x03vorbis`/`OpusTags` signature.
+/// Expects the reader to be positioned right after the first `OggS` capture pattern.
+fn read_ogg_comment_packet<R: Read>(file: &mut R) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // The comment header is the second packet of the stream
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut first_page = true;
+
+    while packets.len() < 3 {
+        if !first_page {
+            let mut magic = [0u8; 4];
+            file.read_exact(&mut magic)?;
+            if &magic != b"OggS" {
+                return Err("invalid Ogg page".into());
+            }
+        }
+        first_page = false;
+
+        // version, header type, granule position, serial, sequence and checksum
+        let mut header = [0u8; 23];
+        file.read_exact(&mut header)?;
+        let segment_count = header[22] as usize;
+        let mut segments = vec![0u8; segment_count];
+        file.read_exact(&mut segments)?;
+
+        for lacing in segments {
+            let mut data = vec![0u8; lacing as usize];
+            file.read_exact(&mut data)?;
+            packets.last_mut().unwrap().extend_from_slice(&data);
+            if lacing < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+
+    let packet = &packets[1];
+    if packet.starts_with(b"\x03vorbis") {
+        Ok(packet[7..].to_vec())
+    } else if packet.starts_with(b"OpusTags") {
+        Ok(packet[8..].to_vec())
+    } else {
+        Err("unsupported Ogg codec".into())
+    }
+}
+
+/// Parses a Vorbis comment block: a vendor string followed by `KEY=value` entries.
+fn parse_vorbis_comment_block(
+    block: &[u8],
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let mut cursor = 0;
+    let read_u32 = |cursor: &mut usize| -> Result<usize, Box<dyn std::error::Error>> {
+        let bytes = block
+            .get(*cursor..*cursor + 4)
+            .ok_or("truncated comment block")?;
+        *cursor += 4;
+        Ok(u32::from_le_bytes(bytes.try_into()?) as usize)
+    };
+
+    let vendor_length = read_u32(&mut cursor)?;
+    cursor += vendor_length;
+    let count = read_u32(&mut cursor)?;
+
+    let mut comments = HashMap::new();
+    for _ in 0..count {
+        let length = read_u32(&mut cursor)?;
+        let entry = block
+            .get(cursor..cursor + length)
+            .ok_or("truncated comment block")?;
+        cursor += length;
+
+        if let Some((key, value)) = String::from_utf8_lossy(entry).split_once('=') {
+            comments.insert(key.to_uppercase(), value.to_string());
+        }
+    }
+
+    Ok(comments)
+}
+
+/// A `Source` that plays the intro of a track once, then repeats the loop region
+/// `loop_count` times (forever if `0`) and fades out while looping one last time.
+///
+/// Every repetition (and any seek) seeks the decoder back to the exact sample given
+/// by the loop markers, so only the packet being played is held in memory.
+pub struct LoopSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    inner: S,
+    channels: u16,
+    sample_rate: u32,
+    /// Position in the track of the next sample `inner` yields, in interleaved samples
+    track_offset: usize,
+    /// Loop region in interleaved samples
+    loop_start: usize,
+    loop_end: usize,
+    /// Position in the played (unrolled) timeline, in interleaved samples
+    position: usize,
+    loop_count: u32,
+    fade_length: usize,
+}
+
+impl<S> LoopSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(inner: S, points: LoopPoints, loop_count: u32, fade: Duration) -> Self {
+        let channels = inner.channels();
+        let sample_rate = inner.sample_rate();
+        let frame_to_samples = |frames: u64| (frames * channels as u64) as usize;
+        let fade_frames = fade.as_secs_f64() * sample_rate as f64;
+
+        LoopSource {
+            inner,
+            channels,
+            sample_rate,
+            track_offset: 0,
+            loop_start: frame_to_samples(points.start),
+            loop_end: frame_to_samples(points.start + points.length),
+            position: 0,
+            loop_count,
+            fade_length: frame_to_samples(fade_frames as u64).max(1),
+        }
+    }
+
+    fn loop_length(&self) -> usize {
+        self.loop_end - self.loop_start
+    }
+
+    /// Length of the whole unrolled timeline, `None` when looping forever
+    fn timeline_length(&self) -> Option<usize> {
+        match self.loop_count {
+            0 => None,
+            count => Some(self.loop_end + count as usize * self.loop_length() + self.fade_length),
+        }
+    }
+
+    /// Returns the sample at `offset` in the track, seeking the decoder there first
+    /// unless it is the next one.
+    fn sample_at(&mut self, offset: usize) -> Option<S::Item> {
+        if offset != self.track_offset {
+            let frame = (offset / self.channels as usize) as u64;
+            // Rounded up to the nanosecond, decoders round the time down to a frame
+            let nanos = (frame * 1_000_000_000).div_ceil(self.sample_rate as u64);
+            let pos = Duration::from_nanos(nanos);
+            if let Err(e) = self.inner.try_seek(pos) {
+                log::warn!("Could not seek back to the loop start: {}", e);
+                return None;
+            }
+            self.track_offset = offset;
+        }
+
+        let sample = self.inner.next()?;
+        self.track_offset += 1;
+        Some(sample)
+    }
+}
+
+impl<S> Iterator for LoopSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.loop_length() == 0 {
+            return None;
+        }
+
+        // Map the timeline position back onto the track:
+        // pass 0 is the intro plus the first loop, pass n is the n-th repetition.
+        let (pass, offset) = if self.position < self.loop_end {
+            (0, self.position)
+        } else {
+            let looped = self.position - self.loop_start;
+            (
+                looped / self.loop_length(),
+                self.loop_start + looped % self.loop_length(),
+            )
+        };
+
+        let mut gain = 1.0;
+        if self.loop_count > 0 && pass > self.loop_count as usize {
+            let faded =
+                self.position - (self.loop_end + self.loop_count as usize * self.loop_length());
+            if faded >= self.fade_length {
+                return None;
+            }
+            gain = 1.0 - faded as f32 / self.fade_length as f32;
+        }
+
+        let sample = match self.sample_at(offset) {
+            Some(sample) => sample,
+            None => {
+                // The track is shorter than its loop markers claim, loop what was decoded
+                if pass == 0 && offset == self.track_offset && offset > self.loop_start {
+                    self.loop_end = offset - offset % self.channels as usize;
+                    return self.next();
+                }
+                return None;
+            }
+        };
+
+        self.position += 1;
+        Some(sample.amplify(gain))
+    }
+}
+
+impl<S> Source for LoopSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.timeline_length().map(|length| {
+            Duration::from_secs_f64(
+                length as f64 / (self.sample_rate as f64 * self.channels as f64),
+            )
+        })
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let frame = (pos.as_secs_f64() * self.sample_rate as f64) as usize;
+        let target = frame * self.channels as usize;
+        self.position = match self.timeline_length() {
+            Some(length) => target.min(length),
+            None => target,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::SymphoniaSource;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn test_loop_points_from_comments() {
+        let comments = HashMap::from([
+            ("LOOP_START".to_string(), "100".to_string()),
+            ("LOOP_END".to_string(), "250".to_string()),
+        ]);
+        assert_eq!(
+            loop_points_from_comments(&comments),
+            Some(LoopPoints {
+                start: 100,
+                length: 150
+            })
+        );
+    }
+
+    #[test]
+    fn test_loop_source_repeats_loop_region() {
+        let samples: Vec<i16> = (0..10).collect();
+        let source = SamplesBuffer::new(1, 10, samples);
+        let points = LoopPoints {
+            start: 4,
+            length: 4,
+        };
+        let played: Vec<i16> =
+            LoopSource::new(source, points, 1, Duration::from_millis(200)).collect();
+
+        // intro + loop, one repetition, then two faded samples of the loop
+        assert_eq!(&played[..12], &[0, 1, 2, 3, 4, 5, 6, 7, 4, 5, 6, 7]);
+        assert_eq!(played.len(), 14);
+        assert_eq!(played[12], 4);
+    }
+
+    #[test]
+    fn test_loop_source_seeks_into_repetition() {
+        let samples: Vec<i16> = (0..20).collect();
+        let source = SamplesBuffer::new(2, 10, samples);
+        let points = LoopPoints {
+            start: 2,
+            length: 3,
+        };
+        let mut source = LoopSource::new(source, points, 2, Duration::from_millis(100));
+
+        // Frame 6 of the timeline is frame 3 of the track, in the first repetition
+        source.try_seek(Duration::from_millis(600)).unwrap();
+        let played: Vec<i16> = source.by_ref().take(6).collect();
+        assert_eq!(played, [6, 7, 8, 9, 4, 5]);
+    }
+
+    #[test]
+    fn test_loop_source_seeks_decoder_exactly() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/tone.flac");
+        let track: Vec<f32> = SymphoniaSource::open(path).unwrap().collect();
+        let points = LoopPoints {
+            start: 5000,
+            length: 7000,
+        };
+        let source = SymphoniaSource::open(path).unwrap();
+        let played: Vec<f32> = LoopSource::new(source, points, 1, Duration::from_millis(10))
+            .take(19000)
+            .collect();
+
+        // The repetition starts on the loop start sample, not a packet boundary
+        assert_eq!(&played[..12000], &track[..12000]);
+        assert_eq!(&played[12000..], &track[5000..12000]);
+    }
+
+    #[test]
+    fn test_loop_source_shorter_track() {
+        let samples: Vec<i16> = (0..6).collect();
+        let source = SamplesBuffer::new(1, 10, samples);
+        let points = LoopPoints {
+            start: 2,
+            length: 10,
+        };
+        let played: Vec<i16> = LoopSource::new(source, points, 1, Duration::from_millis(100))
+            .take(10)
+            .collect();
+
+        // The loop ends with the track
+        assert_eq!(played, [0, 1, 2, 3, 4, 5, 2, 3, 4, 5]);
+    }
+}
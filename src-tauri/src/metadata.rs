@@ -0,0 +1,346 @@
+use crate::decoder::probe_duration;
+use lofty::config::WriteOptions;
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
+use lofty::tag::{Accessor, ItemKey, Tag, TagType};
+use serde_derive::{Deserialize, Serialize};
+use std::time::UNIX_EPOCH;
+
+/// Tags of a track file, whatever format they were stored in.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Duration in seconds
+    pub duration: Option<u64>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    /// Audio bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    /// Size of the file in bytes
+    pub file_size: Option<u64>,
+    /// Last modification time of the file, in seconds since the Unix epoch
+    pub mtime: Option<i64>,
+}
+
+/// Takes the first value of a field found in any tag of the file,
+/// the primary tag of the format (e.g. ID3v2 for MP3) is looked at first.
+fn first_of<T, F>(tagged_file: &TaggedFile, get: F) -> Option<T>
+where
+    F: Fn(&Tag) -> Option<T>,
+{
+    tagged_file
+        .primary_tag()
+        .and_then(&get)
+        .or_else(|| tagged_file.tags().iter().find_map(&get))
+}
+
+/// `first_of` for text fields, blank values count as missing.
+fn first_text<F>(tagged_file: &TaggedFile, get: F) -> Option<String>
+where
+    F: Fn(&Tag) -> Option<String>,
+{
+    first_of(tagged_file, |tag| {
+        get(tag)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// Fills in the size and modification time of the file.
+fn with_file_info(mut metadata: TrackMetadata, path: &str) -> TrackMetadata {
+    if let Ok(file) = std::fs::metadata(path) {
+        metadata.file_size = Some(file.len());
+        metadata.mtime = file
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs() as i64);
+    }
+    metadata
+}
+
+/// Reads the tags of any supported track file: ID3v1/v2, APEv2, Vorbis comments (FLAC, Ogg
+/// Vorbis/Opus), MP4 atoms and RIFF INFO chunks. MIDI files are read by `midi::parse_midi_tags`.
+pub fn read_track_metadata(path: &str) -> Result<TrackMetadata, Box<dyn std::error::Error>> {
+    if crate::midi::is_midi_file(path) {
+        return Ok(with_file_info(crate::midi::parse_midi_tags(path)?, path));
+    }
+
+    let tagged_file = lofty::read_from_path(path)?;
+    let properties = tagged_file.properties();
+
+    // `probe_duration` walks the frames of MP3 files without a VBR header, where the
+    // properties are only estimated. Other containers store the length of the stream.
+    let properties_duration = Some(properties.duration())
+        .filter(|duration| !duration.is_zero() && tagged_file.file_type() != FileType::Mpeg);
+    let duration = properties_duration
+        .or_else(|| probe_duration(path))
+        .map(|duration| duration.as_secs());
+
+    let metadata = TrackMetadata {
+        title: first_text(&tagged_file, |tag| tag.title().map(|s| s.to_string())),
+        artist: first_text(&tagged_file, |tag| tag.artist().map(|s| s.to_string())),
+        album: first_text(&tagged_file, |tag| tag.album().map(|s| s.to_string())),
+        duration,
+        track_number: first_of(&tagged_file, |tag| tag.track()),
+        disc_number: first_of(&tagged_file, |tag| tag.disk()),
+        year: first_of(&tagged_file, |tag| tag.year()),
+        genre: first_text(&tagged_file, |tag| tag.genre().map(|s| s.to_string())),
+        album_artist: first_text(&tagged_file, |tag| {
+            tag.get_string(&ItemKey::AlbumArtist).map(|s| s.to_string())
+        }),
+        composer: first_text(&tagged_file, |tag| {
+            tag.get_string(&ItemKey::Composer).map(|s| s.to_string())
+        }),
+        comment: first_text(&tagged_file, |tag| tag.comment().map(|s| s.to_string())),
+        bitrate: properties.audio_bitrate().or(properties.overall_bitrate()),
+        sample_rate: properties.sample_rate(),
+        ..Default::default()
+    };
+
+    Ok(with_file_info(metadata, path))
+}
+
+/// Edits to apply to the tags of a track. `None` leaves a field as is,
+/// an empty string (or `0` for numbers) removes it.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TagChanges {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub year: Option<u32>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+}
+
+/// A field whose value differs between two versions of the tags.
+#[derive(Debug, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl TrackMetadata {
+    /// The tags as they will read after `changes` are written.
+    pub fn with_changes(&self, changes: &TagChanges) -> TrackMetadata {
+        fn text(current: &Option<String>, change: &Option<String>) -> Option<String> {
+            match change {
+                Some(value) if value.trim().is_empty() => None,
+                Some(value) => Some(value.trim().to_string()),
+                None => current.clone(),
+            }
+        }
+        fn number(current: Option<u32>, change: Option<u32>) -> Option<u32> {
+            match change {
+                Some(0) => None,
+                Some(value) => Some(value),
+                None => current,
+            }
+        }
+
+        TrackMetadata {
+            title: text(&self.title, &changes.title),
+            artist: text(&self.artist, &changes.artist),
+            album: text(&self.album, &changes.album),
+            album_artist: text(&self.album_artist, &changes.album_artist),
+            genre: text(&self.genre, &changes.genre),
+            composer: text(&self.composer, &changes.composer),
+            comment: text(&self.comment, &changes.comment),
+            year: number(self.year, changes.year),
+            track_number: number(self.track_number, changes.track_number),
+            disc_number: number(self.disc_number, changes.disc_number),
+            ..self.clone()
+        }
+    }
+
+    /// Lists the tag fields that differ from `other`.
+    pub fn diff(&self, other: &TrackMetadata) -> Vec<FieldChange> {
+        let fields: [(&'static str, Option<String>, Option<String>); 10] = [
+            ("title", self.title.clone(), other.title.clone()),
+            ("artist", self.artist.clone(), other.artist.clone()),
+            ("album", self.album.clone(), other.album.clone()),
+            (
+                "album_artist",
+                self.album_artist.clone(),
+                other.album_artist.clone(),
+            ),
+            ("genre", self.genre.clone(), other.genre.clone()),
+            ("composer", self.composer.clone(), other.composer.clone()),
+            ("comment", self.comment.clone(), other.comment.clone()),
+            (
+                "year",
+                self.year.map(|v| v.to_string()),
+                other.year.map(|v| v.to_string()),
+            ),
+            (
+                "track_number",
+                self.track_number.map(|v| v.to_string()),
+                other.track_number.map(|v| v.to_string()),
+            ),
+            (
+                "disc_number",
+                self.disc_number.map(|v| v.to_string()),
+                other.disc_number.map(|v| v.to_string()),
+            ),
+        ];
+
+        fields
+            .into_iter()
+            .filter(|(_, old, new)| old != new)
+            .map(|(field, old, new)| FieldChange { field, old, new })
+            .collect()
+    }
+}
+
+fn set_text(tag: &mut Tag, key: ItemKey, value: &Option<String>) {
+    match value {
+        Some(value) => {
+            tag.insert_text(key, value.clone());
+        }
+        None => {
+            tag.remove_key(&key);
+        }
+    }
+}
+
+/// Applies the final values of `metadata` to one tag of the file.
+fn apply_to_tag(tag: &mut Tag, metadata: &TrackMetadata) {
+    set_text(tag, ItemKey::TrackTitle, &metadata.title);
+    set_text(tag, ItemKey::TrackArtist, &metadata.artist);
+    set_text(tag, ItemKey::AlbumTitle, &metadata.album);
+    set_text(tag, ItemKey::AlbumArtist, &metadata.album_artist);
+    set_text(tag, ItemKey::Genre, &metadata.genre);
+    set_text(tag, ItemKey::Composer, &metadata.composer);
+    set_text(tag, ItemKey::Comment, &metadata.comment);
+
+    match metadata.year {
+        Some(year) => tag.set_year(year),
+        None => tag.remove_year(),
+    }
+    match metadata.track_number {
+        Some(track) => tag.set_track(track),
+        None => tag.remove_track(),
+    }
+    match metadata.disc_number {
+        Some(disc) => tag.set_disk(disc),
+        None => tag.remove_disk(),
+    }
+}
+
+/// Writes `metadata` to every tag of the file, creating the primary tag of the format
+/// (ID3v2.4 for MP3, Vorbis comments for FLAC/Ogg, MP4 atoms, ...) if the file has none.
+/// Every tag is rewritten, so a stale ID3v1 or APE tag can't show through a removed field.
+pub fn write_track_tags(
+    path: &str,
+    metadata: &TrackMetadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if crate::midi::is_midi_file(path) {
+        return Err("MIDI files have no tags to write".into());
+    }
+
+    let mut tagged_file = lofty::read_from_path(path)?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag_types: Vec<TagType> = tagged_file
+        .tags()
+        .iter()
+        .map(|tag| tag.tag_type())
+        .collect();
+    for tag_type in tag_types {
+        if let Some(tag) = tagged_file.tag_mut(tag_type) {
+            apply_to_tag(tag, metadata);
+        }
+    }
+
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> TrackMetadata {
+        TrackMetadata {
+            title: Some("Song".to_string()),
+            artist: Some("Artist".to_string()),
+            genre: Some("Rock".to_string()),
+            year: Some(1999),
+            track_number: Some(3),
+            duration: Some(180),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_with_changes() {
+        let changes = TagChanges {
+            title: Some("  New Song ".to_string()),
+            album: Some("Album".to_string()),
+            genre: Some(" ".to_string()),
+            year: Some(2001),
+            track_number: Some(0),
+            ..Default::default()
+        };
+        let updated = current().with_changes(&changes);
+
+        // Set, trimmed
+        assert_eq!(updated.title.as_deref(), Some("New Song"));
+        assert_eq!(updated.album.as_deref(), Some("Album"));
+        assert_eq!(updated.year, Some(2001));
+        // Cleared by a blank string or 0
+        assert_eq!(updated.genre, None);
+        assert_eq!(updated.track_number, None);
+        // Unchanged, including the file properties
+        assert_eq!(updated.artist.as_deref(), Some("Artist"));
+        assert_eq!(updated.disc_number, None);
+        assert_eq!(updated.duration, Some(180));
+    }
+
+    #[test]
+    fn test_diff() {
+        let changes = TagChanges {
+            title: Some("New Song".to_string()),
+            artist: Some("Artist".to_string()),
+            composer: Some("Composer".to_string()),
+            genre: Some(String::new()),
+            track_number: Some(0),
+            ..Default::default()
+        };
+        let before = current();
+        let after = before.with_changes(&changes);
+
+        let diff: Vec<(&str, Option<String>, Option<String>)> = before
+            .diff(&after)
+            .into_iter()
+            .map(|change| (change.field, change.old, change.new))
+            .collect();
+        let text = |value: &str| Some(value.to_string());
+        // The artist is set to the value it already had, so it isn't listed
+        assert_eq!(
+            diff,
+            [
+                ("title", text("Song"), text("New Song")),
+                ("genre", text("Rock"), None),
+                ("composer", None, text("Composer")),
+                ("track_number", text("3"), None),
+            ]
+        );
+        assert!(before.diff(&before.clone()).is_empty());
+    }
+}
@@ -0,0 +1,338 @@
+use crate::metadata::TrackMetadata;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use rodio::{source::SeekError, Source};
+use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+/// Frames rendered between two rounds of MIDI event processing
+const RENDER_CHUNK: usize = 64;
+/// Rendering goes on for this long after the last event, so released notes can ring out
+const RELEASE_TAIL: Duration = Duration::from_secs(1);
+
+/// Returns whether the file at `path` is a Standard MIDI File, judging by its extension.
+pub fn is_midi_file(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+        .unwrap_or(false)
+}
+
+/// A channel message of a MIDI file, in the form `Synthesizer::process_midi_message` expects.
+#[derive(Clone, Copy)]
+struct MidiEvent {
+    /// Seconds from the beginning of the file
+    time: f64,
+    channel: i32,
+    command: i32,
+    data1: i32,
+    data2: i32,
+}
+
+impl MidiEvent {
+    /// Notes are skipped when seeking, everything else (programs, controllers, pitch bend)
+    /// is replayed so the channels sound the same as if played from the start.
+    fn is_note(&self) -> bool {
+        matches!(self.command, 0x80 | 0x90 | 0xA0)
+    }
+}
+
+/// A parsed MIDI file: its channel events on an absolute time line and the name meta event.
+struct MidiTimeline {
+    events: Vec<MidiEvent>,
+    title: Option<String>,
+    length: f64,
+}
+
+impl MidiTimeline {
+    fn parse(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let smf = Smf::parse(bytes)?;
+
+        // Merge the tracks into a single list of events, ordered by tick
+        let mut merged = Vec::new();
+        for track in &smf.tracks {
+            let mut tick = 0u64;
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                merged.push((tick, event.kind));
+            }
+        }
+        merged.sort_by_key(|(tick, _)| *tick);
+
+        // Convert ticks to seconds, following the tempo map
+        let mut seconds_per_tick = match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => 0.5 / ticks_per_beat.as_int() as f64,
+            Timing::Timecode(fps, subframes) => 1.0 / (fps.as_f32() as f64 * subframes as f64),
+        };
+        let mut last_tick = 0;
+        let mut time = 0.0;
+
+        let mut events = Vec::new();
+        let mut title = None;
+        for (tick, kind) in merged {
+            time += (tick - last_tick) as f64 * seconds_per_tick;
+            last_tick = tick;
+
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat)) => {
+                    if let Timing::Metrical(ticks_per_beat) = smf.header.timing {
+                        seconds_per_tick = us_per_beat.as_int() as f64
+                            / 1_000_000.0
+                            / ticks_per_beat.as_int() as f64;
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) if title.is_none() => {
+                    let name = String::from_utf8_lossy(name).trim().to_string();
+                    if !name.is_empty() {
+                        title = Some(name);
+                    }
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let (command, data1, data2) = match message {
+                        MidiMessage::NoteOff { key, vel } => (0x80, key.as_int(), vel.as_int()),
+                        MidiMessage::NoteOn { key, vel } => (0x90, key.as_int(), vel.as_int()),
+                        MidiMessage::Aftertouch { key, vel } => (0xA0, key.as_int(), vel.as_int()),
+                        MidiMessage::Controller { controller, value } => {
+                            (0xB0, controller.as_int(), value.as_int())
+                        }
+                        MidiMessage::ProgramChange { program } => (0xC0, program.as_int(), 0),
+                        MidiMessage::ChannelAftertouch { vel } => (0xD0, vel.as_int(), 0),
+                        MidiMessage::PitchBend { bend } => {
+                            let value = bend.0.as_int();
+                            (0xE0, (value & 0x7f) as u8, (value >> 7) as u8)
+                        }
+                    };
+                    events.push(MidiEvent {
+                        time,
+                        channel: channel.as_int() as i32,
+                        command,
+                        data1: data1 as i32,
+                        data2: data2 as i32,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(MidiTimeline {
+            events,
+            title,
+            length: time,
+        })
+    }
+}
+
+/// Parses the tags of a MIDI file.
+/// The title comes from the first track name meta event, or the file name,
+/// the duration is computed from the tempo map.
+pub fn parse_midi_tags(path: &str) -> Result<TrackMetadata, Box<dyn std::error::Error>> {
+    let timeline = MidiTimeline::parse(&std::fs::read(path)?)?;
+    let title = timeline.title.or_else(|| {
+        Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+    });
+
+    Ok(TrackMetadata {
+        title,
+        duration: Some(timeline.length.round() as u64),
+        ..Default::default()
+    })
+}
+
+/// Loads a SoundFont (`.sf2`), which is needed to render MIDI files.
+pub fn load_soundfont(path: &str) -> Result<Arc<SoundFont>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    Ok(Arc::new(SoundFont::new(&mut file)?))
+}
+
+/// A `Source` rendering a MIDI file through a SoundFont synthesizer, as interleaved stereo.
+pub struct MidiSource {
+    synthesizer: Synthesizer,
+    timeline: MidiTimeline,
+    /// Index of the next event to send to the synthesizer
+    next_event: usize,
+    /// Frames rendered since the beginning of the file
+    frame: u64,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Index of the next sample to emit from the rendered chunk, interleaved
+    chunk_position: usize,
+}
+
+impl MidiSource {
+    pub fn new(
+        path: &str,
+        sound_font: &Arc<SoundFont>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let timeline = MidiTimeline::parse(&std::fs::read(path)?)?;
+        let settings = SynthesizerSettings::new(SAMPLE_RATE as i32);
+        let synthesizer = Synthesizer::new(sound_font, &settings)?;
+
+        Ok(MidiSource {
+            synthesizer,
+            timeline,
+            next_event: 0,
+            frame: 0,
+            left: vec![0.0; RENDER_CHUNK],
+            right: vec![0.0; RENDER_CHUNK],
+            chunk_position: RENDER_CHUNK * 2,
+        })
+    }
+
+    fn time(&self) -> f64 {
+        self.frame as f64 / SAMPLE_RATE as f64
+    }
+
+    /// Sends the events due before the end of the next chunk, then renders it.
+    fn render_chunk(&mut self) -> bool {
+        let end_time = self.time() + RENDER_CHUNK as f64 / SAMPLE_RATE as f64;
+        if self.time() >= self.timeline.length + RELEASE_TAIL.as_secs_f64() {
+            return false;
+        }
+
+        while let Some(event) = self.timeline.events.get(self.next_event) {
+            if event.time >= end_time {
+                break;
+            }
+            self.synthesizer.process_midi_message(
+                event.channel,
+                event.command,
+                event.data1,
+                event.data2,
+            );
+            self.next_event += 1;
+        }
+
+        self.synthesizer.render(&mut self.left, &mut self.right);
+        self.frame += RENDER_CHUNK as u64;
+        self.chunk_position = 0;
+        true
+    }
+}
+
+impl Iterator for MidiSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.chunk_position >= RENDER_CHUNK * 2 && !self.render_chunk() {
+            return None;
+        }
+
+        let frame = self.chunk_position / 2;
+        let sample = match self.chunk_position % 2 {
+            0 => self.left[frame],
+            _ => self.right[frame],
+        };
+        self.chunk_position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for MidiSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(self.timeline.length) + RELEASE_TAIL)
+    }
+
+    /// Seeks by resetting the synthesizer and replaying every non-note event before `pos`,
+    /// so programs, controllers and pitch bends are in the state they would be at `pos`.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let target = pos.as_secs_f64();
+
+        self.synthesizer.reset();
+        self.next_event = 0;
+        while let Some(event) = self.timeline.events.get(self.next_event) {
+            if event.time >= target {
+                break;
+            }
+            if !event.is_note() {
+                self.synthesizer.process_midi_message(
+                    event.channel,
+                    event.command,
+                    event.data1,
+                    event.data2,
+                );
+            }
+            self.next_event += 1;
+        }
+
+        self.frame = (target * SAMPLE_RATE as f64) as u64;
+        self.chunk_position = RENDER_CHUNK * 2;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A format 1 file at 96 ticks per beat: a tempo track going from 120 to 60 BPM
+    /// after two beats, and a note held for four beats.
+    fn tempo_change_file() -> Vec<u8> {
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x01\x00\x02\x00\x60".to_vec();
+        let tempo_track: &[u8] = &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 500000 us per beat
+            0x00, 0xFF, 0x03, 0x04, b'S', b'o', b'n', b'g', // track name
+            0x81, 0x40, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 1000000 us per beat at tick 192
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let note_track: &[u8] = &[
+            0x00, 0x90, 0x3C, 0x64, // note on
+            0x83, 0x00, 0x80, 0x3C, 0x00, // note off at tick 384
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        for track in [tempo_track, note_track] {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_timeline_follows_tempo_map() {
+        let timeline = MidiTimeline::parse(&tempo_change_file()).unwrap();
+        // Two beats at 120 BPM, then two at 60 BPM
+        assert!((timeline.length - 3.0).abs() < 1e-9);
+        let times: Vec<f64> = timeline.events.iter().map(|e| e.time).collect();
+        assert_eq!(times, [0.0, 3.0]);
+        assert_eq!(timeline.title.as_deref(), Some("Song"));
+    }
+
+    #[test]
+    fn test_parse_midi_tags() {
+        let path = std::env::temp_dir().join(format!("rwave-tempo-{}.mid", std::process::id()));
+        std::fs::write(&path, tempo_change_file()).unwrap();
+
+        let metadata = parse_midi_tags(&path.to_string_lossy()).unwrap();
+        assert_eq!(metadata.duration, Some(3));
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_midi_file() {
+        assert!(is_midi_file("song.mid"));
+        assert!(is_midi_file("/music/Song.MIDI"));
+        assert!(!is_midi_file("song.mp3"));
+        assert!(!is_midi_file("mid"));
+        assert!(!is_midi_file("/music/mid/song"));
+    }
+}
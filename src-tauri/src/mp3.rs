@@ -0,0 +1,305 @@
+use id3::{Tag, TagLike};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// How far to look for the next frame header after garbage between frames
+const RESYNC_WINDOW: usize = 64 * 1024;
+
+/// The fields of an MPEG audio frame header needed to walk the stream.
+#[derive(Clone, Copy)]
+struct FrameHeader {
+    is_mpeg1: bool,
+    is_mono: bool,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    frame_length: usize,
+}
+
+impl FrameHeader {
+    fn parse(bytes: [u8; 4]) -> Option<Self> {
+        if bytes[0] != 0xFF || bytes[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+
+        let version = (bytes[1] >> 3) & 0x03; // 0: MPEG 2.5, 2: MPEG 2, 3: MPEG 1
+        let layer = (bytes[1] >> 1) & 0x03; // 1: Layer III, 2: Layer II, 3: Layer I
+        let bitrate_index = (bytes[2] >> 4) as usize;
+        let sample_rate_index = ((bytes[2] >> 2) & 0x03) as usize;
+        let padding = ((bytes[2] >> 1) & 0x01) as usize;
+        let is_mono = bytes[3] >> 6 == 3;
+
+        // Reserved values, and free format streams whose frame length can't be computed
+        if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+        if sample_rate_index == 3 {
+            return None;
+        }
+
+        let is_mpeg1 = version == 3;
+        let bitrate = match (is_mpeg1, layer) {
+            (true, 3) => [
+                0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+            ],
+            (true, 2) => [
+                0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+            ],
+            (true, _) => [
+                0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+            ],
+            (false, 3) => [
+                0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+            ],
+            (false, _) => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        }[bitrate_index] as usize
+            * 1000;
+        let sample_rate = [44100, 48000, 32000][sample_rate_index]
+            >> match version {
+                3 => 0,
+                2 => 1,
+                _ => 2,
+            };
+
+        let (samples_per_frame, frame_length) = match layer {
+            3 => (384, (12 * bitrate / sample_rate as usize + padding) * 4),
+            2 => (1152, 144 * bitrate / sample_rate as usize + padding),
+            _ if is_mpeg1 => (1152, 144 * bitrate / sample_rate as usize + padding),
+            _ => (576, 72 * bitrate / sample_rate as usize + padding),
+        };
+
+        Some(FrameHeader {
+            is_mpeg1,
+            is_mono,
+            sample_rate,
+            samples_per_frame,
+            frame_length,
+        })
+    }
+
+    /// Offset of the Xing/Info tag from the start of the frame, right after the side information
+    fn xing_offset(&self) -> usize {
+        4 + match (self.is_mpeg1, self.is_mono) {
+            (true, false) => 32,
+            (true, true) | (false, false) => 17,
+            (false, true) => 9,
+        }
+    }
+
+    fn duration_of(&self, samples: u64) -> Duration {
+        Duration::from_secs_f64(samples as f64 / self.sample_rate as f64)
+    }
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads the frame count from a Xing/Info (with LAME delay and padding) or VBRI header,
+/// found in place of the audio of the first frame.
+fn header_duration(frame: &[u8], header: &FrameHeader) -> Option<Duration> {
+    let xing = header.xing_offset();
+    let tag_id = frame.get(xing..xing + 4)?;
+
+    if tag_id == b"Xing" || tag_id == b"Info" {
+        let flags = read_u32_be(frame, xing + 4)?;
+        if flags & 0x01 == 0 {
+            return None;
+        }
+        let frames = read_u32_be(frame, xing + 8)? as u64;
+
+        // The LAME extension follows the optional byte count, TOC and quality fields
+        let mut lame = xing + 12;
+        for (flag, size) in [(0x02, 4), (0x04, 100), (0x08, 4)] {
+            if flags & flag != 0 {
+                lame += size;
+            }
+        }
+        let (delay, padding) = match frame.get(lame..lame + 24) {
+            Some(tag) if tag.starts_with(b"LAME") || tag.starts_with(b"Lavf") => {
+                let delay = ((tag[21] as u64) << 4) | (tag[22] as u64 >> 4);
+                let padding = ((tag[22] as u64 & 0x0F) << 8) | tag[23] as u64;
+                (delay, padding)
+            }
+            _ => (0, 0),
+        };
+
+        let samples = (frames * header.samples_per_frame as u64).saturating_sub(delay + padding);
+        return Some(header.duration_of(samples));
+    }
+
+    // The VBRI header always sits 32 bytes after the frame header
+    if frame.get(36..40)? == b"VBRI" {
+        let frames = read_u32_be(frame, 36 + 14)? as u64;
+        return Some(header.duration_of(frames * header.samples_per_frame as u64));
+    }
+
+    None
+}
+
+/// Returns the size of the ID3v2 tag at the start of the stream, `0` if there is none.
+fn id3v2_size<R: Read + Seek>(reader: &mut R) -> std::io::Result<u64> {
+    let mut header = [0u8; 10];
+    reader.seek(SeekFrom::Start(0))?;
+    if reader.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+/// Finds the next frame header at or after `offset`, requiring the following frame
+/// to be valid too, so a stray `0xFF` byte in the data isn't taken for a header.
+fn sync<R: Read + Seek>(reader: &mut R, offset: u64) -> Option<(u64, FrameHeader)> {
+    let mut window = vec![0u8; RESYNC_WINDOW + 4];
+    reader.seek(SeekFrom::Start(offset)).ok()?;
+    let read = read_up_to(reader, &mut window).ok()?;
+
+    for i in 0..read.saturating_sub(3) {
+        let Some(header) =
+            FrameHeader::parse([window[i], window[i + 1], window[i + 2], window[i + 3]])
+        else {
+            continue;
+        };
+
+        let next = offset + (i + header.frame_length) as u64;
+        let mut bytes = [0u8; 4];
+        reader.seek(SeekFrom::Start(next)).ok()?;
+        let is_followed = match reader.read_exact(&mut bytes) {
+            Ok(_) => FrameHeader::parse(bytes).is_some(),
+            // A single frame before the end of the file
+            Err(_) => true,
+        };
+        if is_followed {
+            return Some((offset + i as u64, header));
+        }
+    }
+
+    None
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Walks every frame header of the stream, skipping over the audio data.
+fn scan_duration<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    first: FrameHeader,
+) -> std::io::Result<Duration> {
+    let mut samples = 0u64;
+    let mut offset = start;
+    let mut header = first;
+
+    loop {
+        samples += header.samples_per_frame as u64;
+        offset += header.frame_length as u64;
+
+        let mut bytes = [0u8; 4];
+        reader.seek(SeekFrom::Start(offset))?;
+        if reader.read_exact(&mut bytes).is_err() {
+            break;
+        }
+
+        header = match FrameHeader::parse(bytes) {
+            Some(next) => next,
+            // Trailing tags (ID3v1, APE) or garbage between frames
+            None => match sync(reader, offset) {
+                Some((next_offset, next)) => {
+                    offset = next_offset;
+                    next
+                }
+                None => break,
+            },
+        };
+    }
+
+    Ok(first.duration_of(samples))
+}
+
+/// Computes the duration of an MP3 file without decoding audio.
+/// Uses the Xing/Info/LAME or VBRI header when present, then the ID3 `TLEN` frame,
+/// and otherwise walks the frame headers, which is exact for VBR files as well.
+pub fn probe_mp3_duration(path: &str) -> Option<Duration> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+
+    let audio_start = id3v2_size(&mut reader).ok()?;
+    let (first_offset, first) = sync(&mut reader, audio_start)?;
+
+    let mut frame = vec![0u8; first.frame_length];
+    reader.seek(SeekFrom::Start(first_offset)).ok()?;
+    let read = read_up_to(&mut reader, &mut frame).ok()?;
+    if let Some(duration) = header_duration(&frame[..read], &first) {
+        return Some(duration);
+    }
+
+    let tlen = Tag::read_from_path(path)
+        .ok()
+        .and_then(|tag| {
+            tag.get("TLEN")?
+                .content()
+                .text()?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .filter(|ms| *ms > 0);
+    if let Some(ms) = tlen {
+        return Some(Duration::from_millis(ms));
+    }
+
+    // Seeking a `BufReader` drops its buffer, the frame walk runs on the file in memory
+    let mut data = Cursor::new(std::fs::read(path).ok()?);
+    scan_duration(&mut data, first_offset, first).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG 1 Layer III, 128 kbit/s, 44100 Hz, stereo: 417 bytes per frame
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    fn frames(count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..count {
+            let mut frame = vec![0u8; 417];
+            frame[..4].copy_from_slice(&HEADER);
+            data.extend(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn test_scan_duration() {
+        let mut reader = Cursor::new(frames(100));
+        let (offset, first) = sync(&mut reader, 0).unwrap();
+        let duration = scan_duration(&mut reader, offset, first).unwrap();
+        assert_eq!(duration.as_millis(), 100 * 1152 * 1000 / 44100);
+    }
+
+    #[test]
+    fn test_xing_header_duration() {
+        let mut data = frames(2);
+        data[36..40].copy_from_slice(b"Xing");
+        data[40..44].copy_from_slice(&1u32.to_be_bytes());
+        data[44..48].copy_from_slice(&1000u32.to_be_bytes());
+
+        let header = FrameHeader::parse(HEADER).unwrap();
+        let duration = header_duration(&data[..417], &header).unwrap();
+        assert_eq!(duration.as_millis(), 1000 * 1152 * 1000 / 44100);
+    }
+}
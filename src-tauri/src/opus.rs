@@ -0,0 +1,124 @@
+use audiopus::coder::{Decoder as LibOpusDecoder, GenericCtl};
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{MutSignals, SampleRate};
+use std::sync::Mutex;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Error, Result};
+use symphonia::core::formats::Packet;
+
+/// Opus always decodes at 48 kHz
+const SAMPLE_RATE: u32 = 48_000;
+
+/// The longest Opus packet holds 120 ms of audio
+const MAX_FRAMES: usize = 5760;
+
+/// A symphonia `Decoder` for Opus, backed by libopus. Symphonia reads Ogg Opus streams,
+/// but ships no decoder for them.
+pub struct OpusDecoder {
+    params: CodecParameters,
+    // libopus decoders may move between threads, but aren't safe to share
+    decoder: Mutex<LibOpusDecoder>,
+    buf: AudioBuffer<f32>,
+    interleaved: Vec<f32>,
+    /// Frames still to drop from the start of the stream, the pre-skip of the Opus header
+    skip: usize,
+}
+
+impl OpusDecoder {
+    fn decode_packet(&mut self, packet: &Packet) -> Result<()> {
+        let channels = self.buf.spec().channels.count();
+        let input = OpusPacket::try_from(packet.buf())
+            .map_err(|_| Error::DecodeError("opus: empty packet"))?;
+        let output = MutSignals::try_from(&mut self.interleaved[..])
+            .map_err(|_| Error::DecodeError("opus: empty output buffer"))?;
+        let frames = self
+            .decoder
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .decode_float(Some(input), output, false)
+            .map_err(|_| Error::DecodeError("opus: invalid packet"))?;
+
+        self.buf.clear();
+        self.buf.render_reserved(Some(frames));
+        for channel in 0..channels {
+            let samples = self.interleaved.iter().skip(channel).step_by(channels);
+            for (out, sample) in self.buf.chan_mut(channel).iter_mut().zip(samples) {
+                *out = *sample;
+            }
+        }
+
+        let skipped = self.skip.min(frames);
+        self.skip -= skipped;
+        self.buf.trim(
+            skipped + packet.trim_start() as usize,
+            packet.trim_end() as usize,
+        );
+        Ok(())
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let channels = match params.channels {
+            Some(channels) => channels,
+            None => return decode_error("opus: missing channel layout"),
+        };
+        // Streams with more than two channels need the libopus multistream decoder
+        let opus_channels = match channels.count() {
+            1 => audiopus::Channels::Mono,
+            2 => audiopus::Channels::Stereo,
+            _ => return unsupported_error("opus: multichannel streams are not supported"),
+        };
+        let decoder = LibOpusDecoder::new(SampleRate::Hz48000, opus_channels)
+            .map_err(|_| Error::DecodeError("opus: could not create the decoder"))?;
+
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            buf: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(SAMPLE_RATE, channels)),
+            interleaved: vec![0.0; MAX_FRAMES * channels.count()],
+            skip: params.delay.unwrap_or(0) as usize,
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[CodecDescriptor {
+            codec: CODEC_TYPE_OPUS,
+            short_name: "opus",
+            long_name: "Opus (libopus)",
+            inst_func: |params, options| Ok(Box::new(OpusDecoder::try_new(params, options)?)),
+        }]
+    }
+
+    fn reset(&mut self) {
+        // A seek lands mid-stream, where the pre-skip doesn't apply anymore
+        let decoder = self.decoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = decoder.reset_state() {
+            log::warn!("Could not reset the Opus decoder: {}", e);
+        }
+        self.skip = 0;
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        if let Err(e) = self.decode_packet(packet) {
+            self.buf.clear();
+            return Err(e);
+        }
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
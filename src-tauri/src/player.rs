@@ -1,21 +1,44 @@
+use crate::db::utils::{parse_chapters, Chapter};
+use crate::decoder::SymphoniaSource;
+use crate::looping::{read_loop_points, LoopSource};
+use crate::midi::{is_midi_file, load_soundfont, MidiSource};
+use crate::settings::Settings;
 use rodio::Source;
+use rustysynth::SoundFont;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::Mutex;
 use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
 use std::time::Duration;
-use std::{io::BufReader, sync::Mutex};
 use tauri::ipc::Channel;
 use uuid::Uuid;
 
 enum PlayerCommand {
     Load(String),
+    Enqueue(String),
     Play,
     Pause,
     Seek(u64),
+    NextChapter,
+    PreviousChapter,
+    ApplySettings(Settings),
     Terminate,
 }
 
+/// A track appended to the rodio sink, the front of the queue is the one playing.
+struct QueuedTrack {
+    path: String,
+    duration: Duration,
+    /// Sorted by start time, unlike the table of contents order of `parse_chapters`
+    chapters: Vec<Chapter>,
+}
+
+/// Within this many milliseconds of a chapter start, `PreviousChapter`
+/// jumps to the chapter before instead of restarting the current one.
+const CHAPTER_RESTART_THRESHOLD_MS: u64 = 3000;
+
 #[derive(Clone, Serialize)]
 #[serde(rename_all = "camelCase", tag = "event", content = "data")]
 pub enum PlayerEvent {
@@ -30,6 +53,99 @@ pub enum PlayerEvent {
         position: u64,
         // duration: u64,
     },
+    /// The previous track ended and the next queued one started playing
+    TrackChanged {
+        path: String,
+    },
+    /// A track could not be played
+    Error {
+        message: String,
+    },
+    /// The file of a track is gone, moved or deleted, see `db::health`
+    FileMissing {
+        path: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ChapterChanged {
+        index: usize,
+        title: Option<String>,
+        start_ms: u64,
+        end_ms: u64,
+    },
+}
+
+/// Returns the index of the chapter containing `position_ms`, if any.
+/// `chapters` must be sorted by start time, the latest one to start wins where they overlap.
+fn chapter_at(chapters: &[Chapter], position_ms: u64) -> Option<usize> {
+    chapters
+        .iter()
+        .rposition(|c| c.start_ms <= position_ms && position_ms < c.end_ms.max(c.start_ms + 1))
+}
+
+/// Open a track for playback, see `open_source`.
+/// Fails with the event to report, `FileMissing` if the file is gone.
+fn open_track(
+    file_path: &str,
+    settings: &Settings,
+    soundfont_cache: &mut Option<(String, Arc<SoundFont>)>,
+) -> Result<(Box<dyn Source<Item = f32> + Send>, QueuedTrack), PlayerEvent> {
+    if !Path::new(file_path).is_file() {
+        return Err(PlayerEvent::FileMissing {
+            path: file_path.to_string(),
+        });
+    }
+    open_source(file_path, settings, soundfont_cache)
+        .map_err(|message| PlayerEvent::Error { message })
+}
+
+/// Open a track for playback, looping it if it carries loop markers.
+/// MIDI files are rendered with the SoundFont from the settings, which is loaded once and cached.
+fn open_source(
+    file_path: &str,
+    settings: &Settings,
+    soundfont_cache: &mut Option<(String, Arc<SoundFont>)>,
+) -> Result<(Box<dyn Source<Item = f32> + Send>, QueuedTrack), String> {
+    let decoder: Box<dyn Source<Item = f32> + Send> = if is_midi_file(file_path) {
+        let soundfont_path = settings
+            .soundfont_path
+            .clone()
+            .ok_or("No SoundFont set, MIDI files can't be played")?;
+        let cached = soundfont_cache
+            .as_ref()
+            .filter(|(cached_path, _)| *cached_path == soundfont_path)
+            .map(|(_, sound_font)| Arc::clone(sound_font));
+        let sound_font = match cached {
+            Some(sound_font) => sound_font,
+            None => {
+                let sound_font = load_soundfont(&soundfont_path).map_err(|e| e.to_string())?;
+                *soundfont_cache = Some((soundfont_path, Arc::clone(&sound_font)));
+                sound_font
+            }
+        };
+        let midi = MidiSource::new(file_path, &sound_font).map_err(|e| e.to_string())?;
+        Box::new(midi)
+    } else {
+        Box::new(SymphoniaSource::open(file_path).map_err(|e| e.to_string())?)
+    };
+
+    let source: Box<dyn Source<Item = f32> + Send> = match read_loop_points(file_path) {
+        Some(points) => Box::new(LoopSource::new(
+            decoder,
+            points,
+            settings.loop_count,
+            Duration::from_millis(settings.loop_fade_ms),
+        )),
+        None => decoder,
+    };
+
+    let mut chapters = parse_chapters(file_path).unwrap_or_default();
+    chapters.sort_by_key(|c| c.start_ms);
+    let track = QueuedTrack {
+        path: file_path.to_string(),
+        duration: source.total_duration().unwrap_or_default(),
+        chapters,
+    };
+    Ok((source, track))
 }
 
 pub struct Player {
@@ -52,18 +168,65 @@ impl Player {
             let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
             let sink = Arc::new(rodio::Sink::try_new(&handle).unwrap()); // A rodio::Sink to play the track
             let event_sender = Arc::new(event_sender);
-            let total_duration = Arc::new(Mutex::new(Duration::from_secs(0)));
+            let queue = Arc::new(Mutex::new(VecDeque::<QueuedTrack>::new()));
+            let current_chapter = Arc::new(Mutex::new(None::<usize>));
+            let mut settings = Settings::default();
+            let mut soundfont_cache = None;
 
             let sink_cln = Arc::clone(&sink);
             let event_sender_cln = Arc::clone(&event_sender);
-            let total_duration_cln = Arc::clone(&total_duration);
+            let queue_cln = Arc::clone(&queue);
+            let current_chapter_cln = Arc::clone(&current_chapter);
             std::thread::spawn(move || loop {
+                let mut queue_guard = queue_cln.lock().unwrap();
+
+                // The sink moved on to the next queued track
+                if sink_cln.len() < queue_guard.len() {
+                    while sink_cln.len() < queue_guard.len() {
+                        queue_guard.pop_front();
+                    }
+                    *current_chapter_cln.lock().unwrap() = None;
+                    if let Some(track) = queue_guard.front() {
+                        event_sender_cln
+                            .send(PlayerEvent::TrackChanged {
+                                path: track.path.clone(),
+                            })
+                            .unwrap();
+                    }
+                }
+
+                let position = sink_cln.get_pos();
+                let (duration, chapters) = match queue_guard.front() {
+                    Some(track) => (track.duration, track.chapters.as_slice()),
+                    None => (Duration::from_secs(0), &[][..]),
+                };
                 event_sender_cln
                     .send(PlayerEvent::PositionUpdate {
-                        position: sink_cln.get_pos().as_secs(),
-                        duration: total_duration_cln.lock().unwrap().as_secs(),
+                        position: position.as_secs(),
+                        duration: duration.as_secs(),
                     })
                     .unwrap();
+
+                // Emit `ChapterChanged` whenever the position crosses a chapter boundary
+                let index = chapter_at(chapters, position.as_millis() as u64);
+                let mut current_guard = current_chapter_cln.lock().unwrap();
+                if index != *current_guard {
+                    *current_guard = index;
+                    if let Some(index) = index {
+                        let chapter = &chapters[index];
+                        event_sender_cln
+                            .send(PlayerEvent::ChapterChanged {
+                                index,
+                                title: chapter.title.clone(),
+                                start_ms: chapter.start_ms,
+                                end_ms: chapter.end_ms,
+                            })
+                            .unwrap();
+                    }
+                }
+                drop(current_guard);
+                drop(queue_guard);
+
                 std::thread::sleep(std::time::Duration::from_millis(100));
                 // std::thread::sleep(std::time::Duration::from_millis(500));
             });
@@ -71,16 +234,37 @@ impl Player {
             'playback_receive_loop: loop {
                 match receiver.recv().unwrap() {
                     PlayerCommand::Load(file_path) => {
+                        // Hold the queue while the sink is refilled, so the position thread
+                        // doesn't mistake the cleared sink for a finished track
+                        let mut queue_guard = queue.lock().unwrap();
                         sink.clear();
-                        let file = std::fs::File::open(file_path).unwrap();
-                        let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-                        let mut duration_guard = total_duration.lock().unwrap();
-                        *duration_guard = source.total_duration().unwrap();
-                        event_sender.send(PlayerEvent::Playing).unwrap();
-                        sink.append(source);
-                        sink.play();
+                        queue_guard.clear();
+                        *current_chapter.lock().unwrap() = None;
+                        match open_track(&file_path, &settings, &mut soundfont_cache) {
+                            Ok((source, track)) => {
+                                queue_guard.push_back(track);
+                                event_sender.send(PlayerEvent::Playing).unwrap();
+                                sink.append(source);
+                                sink.play();
+                            }
+                            Err(event) => {
+                                event_sender.send(event).unwrap();
+                            }
+                        }
                         // soundtrack.lock().unwrap().load(file_path);
                     }
+                    PlayerCommand::Enqueue(file_path) => {
+                        let mut queue_guard = queue.lock().unwrap();
+                        match open_track(&file_path, &settings, &mut soundfont_cache) {
+                            Ok((source, track)) => {
+                                queue_guard.push_back(track);
+                                sink.append(source);
+                            }
+                            Err(event) => {
+                                event_sender.send(event).unwrap();
+                            }
+                        }
+                    }
                     PlayerCommand::Play => {
                         event_sender.send(PlayerEvent::Playing).unwrap();
                         // soundtrack.lock().unwrap().play();
@@ -93,10 +277,70 @@ impl Player {
                     }
                     PlayerCommand::Seek(position) => {
                         // soundtrack.lock().unwrap().seek(position);
-                        sink.try_seek(Duration::from_secs(position)).unwrap();
-                        event_sender
-                            .send(PlayerEvent::Seeked { position: position })
-                            .unwrap();
+                        match sink.try_seek(Duration::from_secs(position)) {
+                            Ok(_) => event_sender.send(PlayerEvent::Seeked { position }).unwrap(),
+                            Err(e) => event_sender
+                                .send(PlayerEvent::Error {
+                                    message: format!("Seek failed: {}", e),
+                                })
+                                .unwrap(),
+                        }
+                    }
+                    PlayerCommand::NextChapter => {
+                        let position_ms = sink.get_pos().as_millis() as u64;
+                        let target = queue.lock().unwrap().front().and_then(|track| {
+                            track
+                                .chapters
+                                .iter()
+                                .map(|c| c.start_ms)
+                                .find(|start| *start > position_ms)
+                        });
+                        if let Some(start_ms) = target {
+                            if sink.try_seek(Duration::from_millis(start_ms)).is_ok() {
+                                event_sender
+                                    .send(PlayerEvent::Seeked {
+                                        position: start_ms / 1000,
+                                    })
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    PlayerCommand::PreviousChapter => {
+                        let position_ms = sink.get_pos().as_millis() as u64;
+                        let queue_guard = queue.lock().unwrap();
+                        let chapters = match queue_guard.front() {
+                            Some(track) => track.chapters.as_slice(),
+                            None => &[][..],
+                        };
+                        // Restart the current chapter, or go to the previous one
+                        // if the current chapter has only just started
+                        let target = match chapter_at(chapters, position_ms) {
+                            Some(index)
+                                if position_ms - chapters[index].start_ms
+                                    < CHAPTER_RESTART_THRESHOLD_MS =>
+                            {
+                                index.checked_sub(1).map(|i| chapters[i].start_ms)
+                            }
+                            Some(index) => Some(chapters[index].start_ms),
+                            None => chapters
+                                .iter()
+                                .map(|c| c.start_ms)
+                                .rfind(|start| *start < position_ms),
+                        };
+                        drop(queue_guard);
+                        if let Some(start_ms) = target {
+                            if sink.try_seek(Duration::from_millis(start_ms)).is_ok() {
+                                event_sender
+                                    .send(PlayerEvent::Seeked {
+                                        position: start_ms / 1000,
+                                    })
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    PlayerCommand::ApplySettings(new_settings) => {
+                        // Applies to tracks loaded from now on
+                        settings = new_settings;
                     }
                     PlayerCommand::Terminate => {
                         break 'playback_receive_loop;
@@ -184,6 +428,14 @@ impl Player {
             .unwrap(); // It could error
     }
 
+    /// Player API: Append a track to the player track queue, it plays once the current track ends
+    pub fn enqueue(&self, file_path: &str) {
+        let sender = self.get_channel();
+        sender
+            .send(PlayerCommand::Enqueue(file_path.to_string()))
+            .unwrap();
+    }
+
     /// Player API: Play the track in the player track queue
     pub fn play(&self) {
         let sender = self.get_channel();
@@ -201,6 +453,25 @@ impl Player {
         sender.send(PlayerCommand::Seek(position)).unwrap();
     }
 
+    /// Player API: Seek to the start of the next chapter of the current track
+    pub fn next_chapter(&self) {
+        let sender = self.get_channel();
+        sender.send(PlayerCommand::NextChapter).unwrap();
+    }
+
+    /// Player API: Seek to the start of the current chapter, or the previous one
+    /// if the current chapter has only just started
+    pub fn previous_chapter(&self) {
+        let sender = self.get_channel();
+        sender.send(PlayerCommand::PreviousChapter).unwrap();
+    }
+
+    /// Player API: Apply the user settings (loop playback, SoundFont) to the tracks loaded from now on
+    pub fn apply_settings(&self, settings: Settings) {
+        let sender = self.get_channel();
+        sender.send(PlayerCommand::ApplySettings(settings)).unwrap();
+    }
+
     /// Subscribe to player events, return the subscription id
     pub fn subscribe_event(&mut self, channel: Channel<PlayerEvent>) -> String {
         let uuid = Uuid::new_v4().to_string();
@@ -223,3 +494,44 @@ impl Drop for Player {
         self.terminate();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(element_id: &str, start_ms: u64, end_ms: u64) -> Chapter {
+        Chapter {
+            element_id: element_id.to_string(),
+            title: None,
+            start_ms,
+            end_ms,
+        }
+    }
+
+    #[test]
+    fn test_chapter_at() {
+        let chapters = [
+            chapter("intro", 0, 1000),
+            chapter("verse", 1000, 5000),
+            chapter("bridge", 4000, 6000),
+            chapter("outro", 8000, 9000),
+        ];
+        assert_eq!(chapter_at(&chapters, 0), Some(0));
+        assert_eq!(chapter_at(&chapters, 999), Some(0));
+        assert_eq!(chapter_at(&chapters, 1000), Some(1));
+        // Overlapping chapters, the one that started last
+        assert_eq!(chapter_at(&chapters, 4500), Some(2));
+        // Between chapters and past the last one
+        assert_eq!(chapter_at(&chapters, 7000), None);
+        assert_eq!(chapter_at(&chapters, 9000), None);
+        assert_eq!(chapter_at(&[], 0), None);
+    }
+
+    #[test]
+    fn test_chapter_at_without_end_time() {
+        let chapters = [chapter("a", 0, 0), chapter("b", 2000, 2000)];
+        assert_eq!(chapter_at(&chapters, 0), Some(0));
+        assert_eq!(chapter_at(&chapters, 2000), Some(1));
+        assert_eq!(chapter_at(&chapters, 1000), None);
+    }
+}
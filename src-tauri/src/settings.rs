@@ -0,0 +1,79 @@
+use crate::player::Player;
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tauri::State;
+
+/// User settings, persisted as `settings.json` in the app config directory.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// How many times the loop region of a looping track (`LOOPSTART`/`LOOPLENGTH`) is repeated
+    /// before fading out, `0` loops forever.
+    pub loop_count: u32,
+    /// Length of the fade out ending a looping track, in milliseconds
+    pub loop_fade_ms: u64,
+    /// Path of the `.sf2` SoundFont used to render MIDI files
+    pub soundfont_path: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            loop_count: 2,
+            loop_fade_ms: 10_000,
+            soundfont_path: None,
+        }
+    }
+}
+
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: Settings,
+}
+
+impl SettingsStore {
+    /// Load the settings from `path`, falling back to the defaults if the file is missing or invalid
+    pub fn load(path: PathBuf) -> Self {
+        let settings = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        SettingsStore { path, settings }
+    }
+
+    pub fn get(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Replace the settings and write them to disk
+    pub fn set(&mut self, settings: Settings) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let content = serde_json::to_string_pretty(&settings).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, content).map_err(|e| e.to_string())?;
+
+        self.settings = settings;
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub fn get_settings(store: State<'_, Mutex<SettingsStore>>) -> Settings {
+    store.lock().unwrap().get().clone()
+}
+
+/// Save the settings, then apply them to the player. Settings that can't be saved
+/// aren't applied, so the player never runs with settings lost at the next start.
+#[tauri::command]
+pub fn update_settings(
+    store: State<'_, Mutex<SettingsStore>>,
+    player: State<'_, Mutex<Player>>,
+    settings: Settings,
+) -> Result<(), String> {
+    store.lock().unwrap().set(settings.clone())?;
+    player.lock().unwrap().apply_settings(settings);
+    Ok(())
+}
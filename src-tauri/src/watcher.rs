@@ -0,0 +1,131 @@
+use crate::db::import::BATCH_SIZE;
+use crate::db::libraryfolders::{load_folders, mark_missing, sync_file, FolderFilter};
+use crate::db::pool::DbPool;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// Events are collected for this long before being applied,
+/// so a file being copied or retagged is only synced once.
+const DEBOUNCE: Duration = Duration::from_millis(1500);
+
+/// Watches the library folders, importing new files, refreshing changed ones
+/// and flagging deleted ones as missing while rwave runs.
+pub struct LibraryWatcher {
+    watcher: Option<RecommendedWatcher>,
+}
+
+impl LibraryWatcher {
+    /// Starts watching every library folder stored in the database.
+    pub fn spawn(pool: DbPool) -> Self {
+        let (tx, rx) = channel();
+        let watcher = match notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        }) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                log::error!("Could not start the library watcher: {}", e);
+                None
+            }
+        };
+        let events_pool = pool.clone();
+        std::thread::spawn(move || handle_events(&events_pool, rx));
+
+        let mut library_watcher = LibraryWatcher { watcher };
+        if let Ok(conn) = pool.get() {
+            for folder in load_folders(&conn).unwrap_or_default() {
+                library_watcher.watch(&folder.path);
+            }
+        }
+        library_watcher
+    }
+
+    pub fn watch(&mut self, path: &str) {
+        if let Some(watcher) = self.watcher.as_mut() {
+            if let Err(e) = watcher.watch(Path::new(path), RecursiveMode::Recursive) {
+                log::error!("Could not watch {}: {}", path, e);
+            }
+        }
+    }
+
+    pub fn unwatch(&mut self, path: &str) {
+        if let Some(watcher) = self.watcher.as_mut() {
+            let _ = watcher.unwatch(Path::new(path));
+        }
+    }
+}
+
+fn handle_events(pool: &DbPool, rx: Receiver<notify::Result<Event>>) {
+    while let Ok(first) = rx.recv() {
+        let mut pending = HashSet::new();
+        let mut collect = |event: notify::Result<Event>| match event {
+            Ok(event) => pending.extend(event.paths),
+            Err(e) => log::warn!("Library watcher error: {}", e),
+        };
+        collect(first);
+
+        let deadline = Instant::now() + DEBOUNCE;
+        while let Ok(event) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            collect(event);
+        }
+
+        if let Err(e) = apply_changes(pool, pending) {
+            log::error!("Could not apply library changes: {}", e);
+        }
+    }
+}
+
+/// Syncs every changed path with the library folder it belongs to.
+fn apply_changes(pool: &DbPool, paths: HashSet<PathBuf>) -> Result<(), String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    apply_to(&mut conn, paths).map_err(|e| e.to_string())
+}
+
+fn apply_to(conn: &mut rusqlite::Connection, paths: HashSet<PathBuf>) -> rusqlite::Result<()> {
+    let filters: Vec<FolderFilter> = load_folders(conn)?
+        .iter()
+        .filter_map(|folder| FolderFilter::new(folder).ok())
+        .collect();
+
+    // A directory moved or copied into a folder stands for the files under it
+    let mut changes: Vec<(&FolderFilter, PathBuf)> = Vec::new();
+    for path in paths {
+        // The innermost folder wins when library folders are nested
+        let filter = filters
+            .iter()
+            .filter(|filter| path.starts_with(filter.root()))
+            .max_by_key(|filter| filter.root().components().count());
+        let Some(filter) = filter else {
+            continue;
+        };
+
+        if path.is_dir() {
+            let files = WalkDir::new(&path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|entry| entry.file_type().is_file());
+            changes.extend(files.map(|entry| (filter, entry.into_path())));
+        } else {
+            changes.push((filter, path));
+        }
+    }
+
+    // A failing file is logged and the others are still synced
+    for batch in changes.chunks(BATCH_SIZE) {
+        let tx = conn.transaction()?;
+        for (filter, path) in batch {
+            if path.is_file() {
+                if let Err(e) = sync_file(&tx, filter, path) {
+                    log::warn!("Could not sync {}: {}", path.display(), e);
+                }
+            } else if let Err(e) = mark_missing(&tx, path) {
+                log::warn!("Could not flag {} as missing: {}", path.display(), e);
+            }
+        }
+        tx.commit()?;
+    }
+    Ok(())
+}